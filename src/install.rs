@@ -8,6 +8,18 @@ fn main() {
 #[cfg(not(windows))]
 use anyhow::Error;
 
+/// Create the service token if missing; `--print-token` echoes it for the desktop app
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+fn install_token() -> anyhow::Result<()> {
+    use ssrapid_desktop_service::token::{load_or_create_token, token_file_path};
+
+    let token = load_or_create_token(&token_file_path())?;
+    if env::args().any(|arg| arg == "--print-token") {
+        println!("{}", token);
+    }
+    Ok(())
+}
+
 #[cfg(target_os = "macos")]
 fn main() -> Result<(), Error> {
     use ssrapid_desktop_service::utils::{run_command, uninstall_old_service};
@@ -17,6 +29,7 @@ fn main() -> Result<(), Error> {
 
     let debug = env::args().any(|arg| arg == "--debug");
     let _ = uninstall_old_service();
+    install_token()?;
 
     let service_binary_path = env::current_exe()
         .unwrap()
@@ -129,16 +142,18 @@ fn main() -> Result<(), Error> {
         return Err(anyhow::anyhow!("ssrapid-desktop-service binary not found"));
     }
 
+    install_token()?;
+
     // Check service status
     let status_output = std::process::Command::new("systemctl")
-        .args(&["status", &format!("{}.service", SERVICE_NAME), "--no-pager"])
+        .args(["status", &format!("{}.service", SERVICE_NAME), "--no-pager"])
         .output()
         .map_err(|e| anyhow::anyhow!("Failed to check service status: {}", e))?;
 
     match status_output.status.code() {
         Some(0) => return Ok(()), // Service is running
         Some(1) | Some(2) | Some(3) => {
-            let _ = run_command(
+            run_command(
                 "systemctl",
                 &["start", &format!("{}.service", SERVICE_NAME)],
                 debug,
@@ -181,6 +196,11 @@ fn main() -> windows_service::Result<()> {
         service_manager::{ServiceManager, ServiceManagerAccess},
     };
 
+    if let Err(e) = install_token() {
+        eprintln!("{e}");
+        std::process::exit(3);
+    }
+
     let manager_access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
     let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
 
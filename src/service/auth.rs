@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use warp::{reject::Reject, Filter, Rejection};
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+/// Require `Authorization: Bearer <token>` matching the service token
+pub fn bearer_token(token: Arc<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                let presented = header
+                    .as_deref()
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .map(str::trim);
+                match presented {
+                    Some(presented) if constant_time_eq(presented, &token) => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::{http::StatusCode, Reply};
+
+    fn authorized() -> impl Filter<Extract = (impl Reply,), Error = std::convert::Infallible> + Clone
+    {
+        bearer_token(Arc::new("secret-token".to_string()))
+            .map(|| "ok")
+            .recover(super::super::handle_rejection)
+    }
+
+    async fn assert_unauthorized(authorization: Option<&str>) {
+        let mut request = warp::test::request().path("/version");
+        if let Some(value) = authorization {
+            request = request.header("authorization", value);
+        }
+        let response = request.reply(&authorized()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "code": 401, "msg": "unauthorized", "data": null })
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_header() {
+        assert_unauthorized(None).await;
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_token() {
+        assert_unauthorized(Some("Bearer wrong-token")).await;
+        assert_unauthorized(Some("Bearer secret-token-2")).await;
+        assert_unauthorized(Some("Bearer ")).await;
+    }
+
+    #[tokio::test]
+    async fn rejects_other_schemes() {
+        assert_unauthorized(Some("Basic secret-token")).await;
+        assert_unauthorized(Some("bearer secret-token")).await;
+        assert_unauthorized(Some("secret-token")).await;
+    }
+
+    #[tokio::test]
+    async fn accepts_service_token() {
+        let response = warp::test::request()
+            .path("/version")
+            .header("authorization", "Bearer secret-token")
+            .reply(&authorized())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "ok");
+    }
+}
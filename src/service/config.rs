@@ -0,0 +1,753 @@
+use super::{data::ResourceLimits, privileges, rotate::RotatePolicy, validate};
+use anyhow::{anyhow, Context, Result};
+use log::LevelFilter;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+const DEFAULT_SOCKET_PATH: &str = "/run/ssrapid/service.sock";
+#[cfg(not(target_os = "linux"))]
+const DEFAULT_SOCKET_PATH: &str = "/var/run/ssrapid/service.sock";
+
+const USAGE: &str = "Usage: ssrapid-desktop-service [OPTIONS]
+
+Options:
+    --config <PATH>            Service config file (TOML)
+    --listen-addr <ADDR>       TCP listen address
+    --port <PORT>              TCP listen port
+    --socket-path <PATH>       Unix socket path
+    --socket-owner <USER>      Unix socket owner
+    --socket-group <GROUP>     Unix socket group
+    --socket-mode <MODE>       Unix socket mode (octal)
+    --log-dir <PATH>           Directory for the service log
+    --log-level <LEVEL>        off, error, warn, info, debug or trace
+    --allowed-core-dir <PATH>  Directory cores may be started from (repeatable)
+    --max-restarts <N>         Crash restarts allowed in a row, 0 disables them
+    --restart-backoff-ms <MS>  Delay before the first crash restart
+    --restart-backoff-max-ms <MS>
+                               Upper bound for the doubling restart delay
+    --stop-grace-ms <MS>       Time the core gets to exit after SIGTERM
+    --ready-timeout-ms <MS>    Time a started core gets to become ready, 0 disables the wait
+    --health-check-interval-secs <SECS>
+                               Probe the core's controller this often, 0 disables
+    --health-check-failures <N>
+                               Failed probes in a row before the core is unhealthy
+    --health-check-restart <BOOL>
+                               Restart the core once it is unhealthy
+    --core-log-max-bytes <N>   Rotate the core log once it reaches this size
+    --core-log-max-age-secs <SECS>
+                               Also rotate the core log after this long, 0 disables
+    --core-log-keep <N>        Rotated core logs to keep
+    --state-file <PATH>        Where the running core is recorded across restarts
+    --core-user <USER>         Run the core as this user with network capabilities only
+    --core-group <GROUP>       Group for --core-user, defaults to its primary group
+    --allowed-core-user <USER> User a start request may ask for (repeatable)
+    --allowed-core-arg <FLAG>  Extra core flag a start request may pass (repeatable)
+    --allowed-core-env <NAME>  Environment variable a start request may set (repeatable)
+    --allowed-core-work-dir <PATH>
+                               Working directory a start request may use (repeatable)";
+
+static CONFIG: OnceCell<ServiceConfig> = OnceCell::new();
+
+/// Daemon settings: defaults, overridden by the config file, then by
+/// `SSRAPID_*` environment variables, then by command line flags.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub listen_addr: IpAddr,
+    pub port: u16,
+    pub socket_path: PathBuf,
+    pub socket_owner: Option<String>,
+    pub socket_group: Option<String>,
+    pub socket_mode: u32,
+    pub log_dir: Option<PathBuf>,
+    pub log_level: String,
+    pub allowed_core_dirs: Vec<PathBuf>,
+    pub max_restarts: u32,
+    pub restart_backoff_ms: u64,
+    pub restart_backoff_max_ms: u64,
+    pub stop_grace_ms: u64,
+    /// How long a start waits for the controller to answer or a listening
+    /// line in the log; 0 reports success as soon as the core is spawned
+    pub ready_timeout_ms: u64,
+    /// How often the core's controller is probed via `/version`; 0 (the
+    /// default) turns health checks off
+    pub health_check_interval_secs: u64,
+    pub health_check_failures: u32,
+    /// Restart the core once it is marked unhealthy instead of only
+    /// reporting it
+    pub health_check_restart: bool,
+    pub core_log_max_bytes: u64,
+    pub core_log_max_age_secs: u64,
+    pub core_log_keep: usize,
+    pub state_file: PathBuf,
+    /// Default limits for the core, set as a `[core_limits]` table in the
+    /// config file; start requests can override single fields
+    pub core_limits: ResourceLimits,
+    /// Run the core as this user, keeping only the network capabilities
+    /// TUN mode needs (Linux only). Its config directory must be writable
+    /// by that user.
+    pub core_user: Option<String>,
+    pub core_group: Option<String>,
+    /// Users start requests may pick with their `user` field
+    pub allowed_core_users: Vec<String>,
+    /// Flags start requests may pass in `args`, e.g. `-ext-ctl`; none by
+    /// default
+    pub allowed_core_args: Vec<String>,
+    /// Variables start requests may set in `env`; none by default
+    pub allowed_core_env: Vec<String>,
+    /// Directories (and their subdirectories) start requests may use as
+    /// `working_dir`; none by default
+    pub allowed_core_work_dirs: Vec<PathBuf>,
+    #[serde(skip_deserializing)]
+    pub config_file: Option<PathBuf>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            listen_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 33211,
+            socket_path: PathBuf::from(DEFAULT_SOCKET_PATH),
+            socket_owner: None,
+            socket_group: None,
+            socket_mode: 0o660,
+            log_dir: None,
+            log_level: "info".into(),
+            allowed_core_dirs: Vec::new(),
+            max_restarts: 5,
+            restart_backoff_ms: 1000,
+            restart_backoff_max_ms: 60_000,
+            stop_grace_ms: 5000,
+            ready_timeout_ms: 10000,
+            health_check_interval_secs: 0,
+            health_check_failures: 3,
+            health_check_restart: false,
+            core_log_max_bytes: 10 * 1024 * 1024,
+            core_log_max_age_secs: 0,
+            core_log_keep: 5,
+            state_file: default_state_path(),
+            core_limits: ResourceLimits::default(),
+            core_user: None,
+            core_group: None,
+            allowed_core_users: Vec::new(),
+            allowed_core_args: Vec::new(),
+            allowed_core_env: Vec::new(),
+            allowed_core_work_dirs: Vec::new(),
+            config_file: None,
+        }
+    }
+}
+
+pub fn default_config_path() -> PathBuf {
+    #[cfg(windows)]
+    {
+        let program_data =
+            std::env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".into());
+        PathBuf::from(program_data)
+            .join("ssrapid")
+            .join("service.toml")
+    }
+
+    #[cfg(not(windows))]
+    {
+        PathBuf::from("/etc/ssrapid/service.toml")
+    }
+}
+
+/// Runtime state lives under /run so it does not outlive a reboot, when the
+/// recorded pid would mean nothing
+pub fn default_state_path() -> PathBuf {
+    #[cfg(windows)]
+    {
+        let program_data =
+            std::env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".into());
+        PathBuf::from(program_data)
+            .join("ssrapid")
+            .join("core-state.json")
+    }
+
+    #[cfg(not(windows))]
+    {
+        Path::new(DEFAULT_SOCKET_PATH).with_file_name("core-state.json")
+    }
+}
+
+/// The loaded configuration; defaults if `init` has not been called
+pub fn get() -> &'static ServiceConfig {
+    CONFIG.get_or_init(ServiceConfig::default)
+}
+
+/// Load the configuration from the process arguments and environment
+pub fn init() -> Result<&'static ServiceConfig> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = ServiceConfig::load(&args)?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+impl ServiceConfig {
+    pub fn load(args: &[String]) -> Result<Self> {
+        let flags = parse_args(args)?;
+
+        // 显式指定的配置文件必须存在，默认路径则可以缺省
+        let explicit = flags
+            .iter()
+            .find(|(name, _)| name == "config")
+            .map(|(_, value)| PathBuf::from(value))
+            .or_else(|| std::env::var_os("SSRAPID_CONFIG").map(PathBuf::from));
+        let mut config = match explicit {
+            Some(path) => Self::from_file(&path)?,
+            None => {
+                let path = default_config_path();
+                if path.exists() {
+                    Self::from_file(&path)?
+                } else {
+                    ServiceConfig::default()
+                }
+            }
+        };
+
+        config.apply_env()?;
+        // 命令行给出的目录替换而不是追加到配置文件中的列表
+        if flags.iter().any(|(name, _)| name == "allowed-core-dir") {
+            config.allowed_core_dirs.clear();
+        }
+        if flags.iter().any(|(name, _)| name == "allowed-core-user") {
+            config.allowed_core_users.clear();
+        }
+        if flags.iter().any(|(name, _)| name == "allowed-core-arg") {
+            config.allowed_core_args.clear();
+        }
+        if flags.iter().any(|(name, _)| name == "allowed-core-env") {
+            config.allowed_core_env.clear();
+        }
+        if flags
+            .iter()
+            .any(|(name, _)| name == "allowed-core-work-dir")
+        {
+            config.allowed_core_work_dirs.clear();
+        }
+        for (name, value) in &flags {
+            config.apply(name, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        let mut config: ServiceConfig = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+        config.config_file = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        const VARS: &[(&str, &str)] = &[
+            ("SSRAPID_LISTEN_ADDR", "listen-addr"),
+            ("SSRAPID_PORT", "port"),
+            ("SSRAPID_SOCKET_PATH", "socket-path"),
+            ("SSRAPID_SOCKET_OWNER", "socket-owner"),
+            ("SSRAPID_SOCKET_GROUP", "socket-group"),
+            ("SSRAPID_SOCKET_MODE", "socket-mode"),
+            ("SSRAPID_LOG_DIR", "log-dir"),
+            ("SSRAPID_LOG_LEVEL", "log-level"),
+            ("SSRAPID_MAX_RESTARTS", "max-restarts"),
+            ("SSRAPID_RESTART_BACKOFF_MS", "restart-backoff-ms"),
+            ("SSRAPID_RESTART_BACKOFF_MAX_MS", "restart-backoff-max-ms"),
+            ("SSRAPID_STOP_GRACE_MS", "stop-grace-ms"),
+            ("SSRAPID_READY_TIMEOUT_MS", "ready-timeout-ms"),
+            (
+                "SSRAPID_HEALTH_CHECK_INTERVAL_SECS",
+                "health-check-interval-secs",
+            ),
+            ("SSRAPID_HEALTH_CHECK_FAILURES", "health-check-failures"),
+            ("SSRAPID_HEALTH_CHECK_RESTART", "health-check-restart"),
+            ("SSRAPID_CORE_LOG_MAX_BYTES", "core-log-max-bytes"),
+            ("SSRAPID_CORE_LOG_MAX_AGE_SECS", "core-log-max-age-secs"),
+            ("SSRAPID_CORE_LOG_KEEP", "core-log-keep"),
+            ("SSRAPID_STATE_FILE", "state-file"),
+            ("SSRAPID_CORE_USER", "core-user"),
+            ("SSRAPID_CORE_GROUP", "core-group"),
+        ];
+        for (var, name) in VARS {
+            if let Ok(value) = std::env::var(var) {
+                self.apply(name, &value)
+                    .with_context(|| format!("Invalid value in {}", var))?;
+            }
+        }
+        if let Some(dirs) = std::env::var_os("SSRAPID_ALLOWED_CORE_DIRS") {
+            self.allowed_core_dirs = std::env::split_paths(&dirs).collect();
+        }
+        if let Ok(users) = std::env::var("SSRAPID_ALLOWED_CORE_USERS") {
+            self.allowed_core_users = split_list(&users);
+        }
+        if let Ok(args) = std::env::var("SSRAPID_ALLOWED_CORE_ARGS") {
+            self.allowed_core_args = split_list(&args);
+        }
+        if let Ok(names) = std::env::var("SSRAPID_ALLOWED_CORE_ENV") {
+            self.allowed_core_env = split_list(&names);
+        }
+        if let Some(dirs) = std::env::var_os("SSRAPID_ALLOWED_CORE_WORK_DIRS") {
+            self.allowed_core_work_dirs = std::env::split_paths(&dirs).collect();
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "config" => {}
+            "listen-addr" => {
+                self.listen_addr = value
+                    .parse()
+                    .with_context(|| format!("Invalid listen address: {}", value))?
+            }
+            "port" => {
+                self.port = value
+                    .parse()
+                    .with_context(|| format!("Invalid port: {}", value))?
+            }
+            "socket-path" => self.socket_path = PathBuf::from(value),
+            "socket-owner" => self.socket_owner = Some(value.to_string()),
+            "socket-group" => self.socket_group = Some(value.to_string()),
+            "socket-mode" => {
+                self.socket_mode = u32::from_str_radix(value.trim_start_matches("0o"), 8)
+                    .with_context(|| format!("Invalid socket mode: {}", value))?
+            }
+            "log-dir" => self.log_dir = Some(PathBuf::from(value)),
+            "log-level" => self.log_level = value.to_string(),
+            "allowed-core-dir" => self.allowed_core_dirs.push(PathBuf::from(value)),
+            "max-restarts" => {
+                self.max_restarts = value
+                    .parse()
+                    .with_context(|| format!("Invalid max restarts: {}", value))?
+            }
+            "restart-backoff-ms" => {
+                self.restart_backoff_ms = value
+                    .parse()
+                    .with_context(|| format!("Invalid restart backoff: {}", value))?
+            }
+            "restart-backoff-max-ms" => {
+                self.restart_backoff_max_ms = value
+                    .parse()
+                    .with_context(|| format!("Invalid restart backoff: {}", value))?
+            }
+            "stop-grace-ms" => {
+                self.stop_grace_ms = value
+                    .parse()
+                    .with_context(|| format!("Invalid stop grace period: {}", value))?
+            }
+            "ready-timeout-ms" => {
+                self.ready_timeout_ms = value
+                    .parse()
+                    .with_context(|| format!("Invalid ready timeout: {}", value))?
+            }
+            "health-check-interval-secs" => {
+                self.health_check_interval_secs = value
+                    .parse()
+                    .with_context(|| format!("Invalid health check interval: {}", value))?
+            }
+            "health-check-failures" => {
+                self.health_check_failures = value
+                    .parse()
+                    .with_context(|| format!("Invalid health check failures: {}", value))?
+            }
+            "health-check-restart" => {
+                self.health_check_restart = value
+                    .parse()
+                    .with_context(|| format!("Invalid health check restart: {}", value))?
+            }
+            "core-log-max-bytes" => {
+                self.core_log_max_bytes = value
+                    .parse()
+                    .with_context(|| format!("Invalid core log size: {}", value))?
+            }
+            "core-log-max-age-secs" => {
+                self.core_log_max_age_secs = value
+                    .parse()
+                    .with_context(|| format!("Invalid core log age: {}", value))?
+            }
+            "core-log-keep" => {
+                self.core_log_keep = value
+                    .parse()
+                    .with_context(|| format!("Invalid core log count: {}", value))?
+            }
+            "state-file" => self.state_file = PathBuf::from(value),
+            "core-user" => self.core_user = Some(value.to_string()),
+            "core-group" => self.core_group = Some(value.to_string()),
+            "allowed-core-user" => self.allowed_core_users.push(value.to_string()),
+            "allowed-core-arg" => self.allowed_core_args.push(value.to_string()),
+            "allowed-core-env" => self.allowed_core_env.push(value.to_string()),
+            "allowed-core-work-dir" => self.allowed_core_work_dirs.push(PathBuf::from(value)),
+            _ => return Err(anyhow!("Unknown option: --{}\n\n{}", name, USAGE)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.log_level_filter()?;
+        let errors = validate::check_limits(&self.core_limits);
+        if !errors.is_empty() {
+            let errors: Vec<String> = errors
+                .iter()
+                .map(|e| format!("core_{}: {}", e.field, e.message))
+                .collect();
+            return Err(anyhow!("Invalid core limits: {}", errors.join("; ")));
+        }
+        if let Some(user) = &self.core_user {
+            privileges::resolve(user, self.core_group.as_deref())?;
+        }
+        for user in &self.allowed_core_users {
+            privileges::resolve(user, self.core_group.as_deref())?;
+        }
+        if let Some(dir) = self.allowed_core_dirs.iter().find(|dir| !dir.is_absolute()) {
+            return Err(anyhow!(
+                "Allowed core directory must be absolute: {}",
+                dir.display()
+            ));
+        }
+        if let Some(arg) = self
+            .allowed_core_args
+            .iter()
+            .find(|arg| validate::flag_name(arg) != Some(arg.as_str()))
+        {
+            return Err(anyhow!(
+                "Allowed core argument must be a bare flag: {}",
+                arg
+            ));
+        }
+        if let Some(name) = self
+            .allowed_core_env
+            .iter()
+            .find(|name| !validate::is_env_name(name))
+        {
+            return Err(anyhow!(
+                "Invalid allowed core environment variable: {}",
+                name
+            ));
+        }
+        if self.health_check_failures == 0 {
+            return Err(anyhow!("Health check failures must be greater than 0"));
+        }
+        // 为 0 时每写一行都会轮转
+        if self.core_log_max_bytes == 0 {
+            return Err(anyhow!("Core log size must be greater than 0"));
+        }
+        if let Some(dir) = self
+            .allowed_core_work_dirs
+            .iter()
+            .find(|dir| !dir.is_absolute())
+        {
+            return Err(anyhow!(
+                "Allowed core working directory must be absolute: {}",
+                dir.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Delay before crash restart number `attempt` (1-based): the base
+    /// backoff doubled per attempt, capped at `restart_backoff_max_ms`
+    pub fn restart_delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(32);
+        let delay = self.restart_backoff_ms.saturating_mul(factor);
+        Duration::from_millis(delay.min(self.restart_backoff_max_ms))
+    }
+
+    pub fn core_log_policy(&self) -> RotatePolicy {
+        RotatePolicy {
+            max_bytes: self.core_log_max_bytes,
+            max_age: match self.core_log_max_age_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            keep: self.core_log_keep,
+        }
+    }
+
+    pub fn log_level_filter(&self) -> Result<LevelFilter> {
+        self.log_level
+            .parse()
+            .map_err(|_| anyhow!("Invalid log level: {}", self.log_level))
+    }
+
+    /// Whether `bin_path` lives under one of `allowed_core_dirs`; any path is
+    /// allowed when the list is empty.
+    pub fn is_allowed_core_path(&self, bin_path: &Path) -> bool {
+        if self.allowed_core_dirs.is_empty() {
+            return true;
+        }
+        is_under(&self.allowed_core_dirs, bin_path)
+    }
+
+    /// Whether `dir` is one of `allowed_core_work_dirs` or below one
+    pub fn is_allowed_work_dir(&self, dir: &Path) -> bool {
+        is_under(&self.allowed_core_work_dirs, dir)
+    }
+}
+
+fn is_under(dirs: &[PathBuf], path: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    dirs.iter()
+        .any(|dir| dir.canonicalize().is_ok_and(|dir| path.starts_with(dir)))
+}
+
+/// Comma-separated list from an environment variable
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Split `--name value` / `--name=value` pairs; `--help` prints usage and exits
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>> {
+    let mut flags = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--help" || arg == "-h" {
+            println!("{}", USAGE);
+            std::process::exit(0);
+        }
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(anyhow!("Unexpected argument: {}\n\n{}", arg, USAGE));
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = iter.next().ok_or(anyhow!("Missing value for --{}", flag))?;
+                (flag.to_string(), value.clone())
+            }
+        };
+        flags.push((name, value));
+    }
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Load `toml` as the config file, followed by the flags in `args`
+    fn load(toml: &str, args: &[&str]) -> Result<ServiceConfig> {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("service.toml");
+        fs::write(&file, toml).unwrap();
+        let mut all = vec!["--config".to_string(), file.display().to_string()];
+        all.extend(args.iter().map(|arg| arg.to_string()));
+        ServiceConfig::load(&all)
+    }
+
+    #[test]
+    fn empty_file_gives_defaults() {
+        let config = load("", &[]).unwrap();
+        let defaults = ServiceConfig::default();
+        assert_eq!(config.listen_addr, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.port, 33211);
+        assert_eq!(config.socket_path, defaults.socket_path);
+        assert_eq!(config.socket_mode, 0o660);
+        assert_eq!(config.log_level, "info");
+        assert_eq!(config.max_restarts, 5);
+        assert_eq!(config.restart_backoff_ms, 1000);
+        assert_eq!(config.restart_backoff_max_ms, 60_000);
+        assert_eq!(config.stop_grace_ms, 5000);
+        assert_eq!(config.ready_timeout_ms, 10000);
+        assert_eq!(config.health_check_interval_secs, 0);
+        assert_eq!(config.health_check_failures, 3);
+        assert!(!config.health_check_restart);
+        assert_eq!(config.core_log_max_bytes, 10 * 1024 * 1024);
+        assert_eq!(config.core_log_max_age_secs, 0);
+        assert_eq!(config.core_log_keep, 5);
+        assert_eq!(config.state_file, default_state_path());
+        assert!(config.core_limits.is_empty());
+        assert!(config.core_user.is_none() && config.core_group.is_none());
+        assert!(config.allowed_core_dirs.is_empty());
+        assert!(config.allowed_core_users.is_empty());
+        assert!(config.allowed_core_args.is_empty());
+        assert!(config.allowed_core_env.is_empty());
+        assert!(config.allowed_core_work_dirs.is_empty());
+        assert!(config.config_file.is_some());
+    }
+
+    #[test]
+    fn rejects_wrongly_typed_keys() {
+        let cases = [
+            "listen_addr = \"localhost\"",
+            "port = 70000",
+            "port = \"33211\"",
+            "socket_path = 1",
+            "socket_owner = 1",
+            "socket_group = false",
+            "socket_mode = \"660\"",
+            "log_dir = []",
+            "log_level = 3",
+            "log_level = \"loud\"",
+            "allowed_core_dirs = \"/opt/core\"",
+            "max_restarts = -1",
+            "restart_backoff_ms = \"1s\"",
+            "restart_backoff_max_ms = 1.5",
+            "stop_grace_ms = true",
+            "ready_timeout_ms = -5",
+            "health_check_interval_secs = \"10\"",
+            "health_check_failures = -1",
+            "health_check_restart = \"yes\"",
+            "core_log_max_bytes = \"10M\"",
+            "core_log_max_age_secs = -1",
+            "core_log_keep = \"all\"",
+            "state_file = 0",
+            "core_limits = 4096",
+            "core_limits = { nofile = \"many\" }",
+            "core_user = 0",
+            "core_group = 0",
+            "allowed_core_users = \"nobody\"",
+            "allowed_core_args = \"-ext-ctl\"",
+            "allowed_core_env = [1]",
+            "allowed_core_work_dirs = \"/srv\"",
+        ];
+        for toml in cases {
+            assert!(load(toml, &[]).is_err(), "accepted {}", toml);
+        }
+    }
+
+    #[test]
+    fn rejects_wrongly_typed_flags() {
+        let cases = [
+            ["--listen-addr", "localhost"],
+            ["--port", "65536"],
+            ["--socket-mode", "rw"],
+            ["--log-level", "loud"],
+            ["--max-restarts", "-1"],
+            ["--restart-backoff-ms", "1s"],
+            ["--restart-backoff-max-ms", "1m"],
+            ["--stop-grace-ms", "5s"],
+            ["--ready-timeout-ms", "ten"],
+            ["--health-check-interval-secs", "-1"],
+            ["--health-check-failures", "0"],
+            ["--health-check-restart", "yes"],
+            ["--core-log-max-bytes", "10M"],
+            ["--core-log-max-age-secs", "1d"],
+            ["--core-log-keep", "all"],
+        ];
+        for args in cases {
+            assert!(load("", &args).is_err(), "accepted {:?}", args);
+        }
+    }
+
+    #[test]
+    fn rejects_zero_core_log_size() {
+        assert!(load("core_log_max_bytes = 0", &[]).is_err());
+        assert!(load("", &["--core-log-max-bytes", "0"]).is_err());
+        let config = load("core_log_max_bytes = 1", &[]).unwrap();
+        assert_eq!(config.core_log_policy().max_bytes, 1);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(load("listen_port = 1", &[]).is_err());
+        assert!(load("[core_limits]\nthreads = 4", &[]).is_err());
+        assert!(load("", &["--listen-port", "1"]).is_err());
+        assert!(load("", &["stray"]).is_err());
+        assert!(load("", &["--port"]).is_err());
+        assert!(
+            ServiceConfig::load(&["--config".into(), "/nonexistent/service.toml".into()]).is_err()
+        );
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let toml = r#"
+listen_addr = "127.0.0.2"
+port = 1234
+socket_path = "/tmp/file.sock"
+socket_mode = 0o600
+log_dir = "/var/log/ssrapid"
+state_file = "/tmp/file-state.json"
+allowed_core_dirs = ["/opt/file"]
+allowed_core_work_dirs = ["/srv/file"]
+"#;
+        let config = load(toml, &[]).unwrap();
+        assert_eq!(config.listen_addr, "127.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(config.port, 1234);
+        assert_eq!(config.socket_path, PathBuf::from("/tmp/file.sock"));
+        assert_eq!(config.socket_mode, 0o600);
+        assert_eq!(config.log_dir, Some(PathBuf::from("/var/log/ssrapid")));
+        assert_eq!(config.state_file, PathBuf::from("/tmp/file-state.json"));
+
+        let config = load(
+            toml,
+            &[
+                "--port=4321",
+                "--socket-path",
+                "/tmp/flag.sock",
+                "--socket-mode",
+                "0o640",
+                "--state-file",
+                "/tmp/flag-state.json",
+                "--allowed-core-dir",
+                "/opt/a",
+                "--allowed-core-dir=/opt/b",
+                "--allowed-core-work-dir",
+                "/srv/flag",
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.port, 4321);
+        assert_eq!(config.socket_path, PathBuf::from("/tmp/flag.sock"));
+        assert_eq!(config.socket_mode, 0o640);
+        assert_eq!(config.state_file, PathBuf::from("/tmp/flag-state.json"));
+        // 命令行中的目录替换配置文件中的列表
+        assert_eq!(
+            config.allowed_core_dirs,
+            [PathBuf::from("/opt/a"), PathBuf::from("/opt/b")]
+        );
+        assert_eq!(config.allowed_core_work_dirs, [PathBuf::from("/srv/flag")]);
+    }
+
+    #[test]
+    fn rejects_relative_directories() {
+        assert!(load("allowed_core_dirs = [\"cores\"]", &[]).is_err());
+        assert!(load("", &["--allowed-core-dir", "cores"]).is_err());
+        assert!(load("allowed_core_work_dirs = [\"work\"]", &[]).is_err());
+        assert!(load("", &["--allowed-core-work-dir", "./work"]).is_err());
+    }
+
+    #[test]
+    fn checks_allowlisted_args_and_env() {
+        let config = load(
+            "allowed_core_args = [\"-ext-ctl\"]\nallowed_core_env = [\"SAFE_PATHS\"]",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.allowed_core_args, ["-ext-ctl"]);
+        assert_eq!(config.allowed_core_env, ["SAFE_PATHS"]);
+        assert!(load("allowed_core_args = [\"-ext-ctl=:9090\"]", &[]).is_err());
+        assert!(load("allowed_core_args = [\"ext-ctl\"]", &[]).is_err());
+        assert!(load("allowed_core_env = [\"BAD-NAME\"]", &[]).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn resolves_core_users() {
+        let config = load("core_user = \"root\"\nallowed_core_users = [\"root\"]", &[]).unwrap();
+        assert_eq!(config.core_user.as_deref(), Some("root"));
+        assert_eq!(config.allowed_core_users, ["root"]);
+        assert!(load("core_user = \"no-such-user-ssrapid\"", &[]).is_err());
+        assert!(load("", &["--allowed-core-user", "no-such-user-ssrapid"]).is_err());
+        assert!(load(
+            "core_user = \"root\"\ncore_group = \"no-such-group-ssrapid\"",
+            &[]
+        )
+        .is_err());
+    }
+}
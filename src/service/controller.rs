@@ -0,0 +1,109 @@
+use super::data::StartBody;
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// The core's external controller (RESTful API), as far as the service can
+/// tell from the start request
+#[derive(Debug, Clone)]
+pub struct Controller {
+    /// `host:port` to connect to
+    addr: String,
+    secret: Option<String>,
+}
+
+impl Controller {
+    /// Taken from `-ext-ctl`/`-secret` in the extra arguments, falling back
+    /// to `external-controller`/`secret` in the config file. `None` if the
+    /// core has no TCP controller.
+    pub fn from_config(config: &StartBody) -> Option<Controller> {
+        let file = std::fs::read_to_string(&config.config_file).unwrap_or_default();
+        let addr = arg_value(&config.args, "-ext-ctl")
+            .or_else(|| top_level_value(&file, "external-controller"))?;
+        let secret =
+            arg_value(&config.args, "-secret").or_else(|| top_level_value(&file, "secret"));
+        Some(Controller {
+            addr: connect_addr(&addr)?,
+            secret: secret.filter(|secret| !secret.is_empty()),
+        })
+    }
+
+    /// `host:port` the service connects to
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// GET `path` and return the HTTP status; any status means the core is
+    /// answering, even 401 for a wrong secret
+    pub async fn get(&self, path: &str, timeout: Duration) -> io::Result<u16> {
+        tokio::time::timeout(timeout, self.request(path))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "controller timed out"))?
+    }
+
+    async fn request(&self, path: &str) -> io::Result<u16> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            path, self.addr
+        );
+        if let Some(secret) = &self.secret {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", secret));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // 只需要状态行
+        let mut buf = [0u8; 64];
+        let mut len = 0;
+        while len < buf.len() && !buf[..len].contains(&b'\n') {
+            match stream.read(&mut buf[len..]).await? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let status_line = String::from_utf8_lossy(&buf[..len]);
+        status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .filter(|_| status_line.starts_with("HTTP/"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an HTTP response"))
+    }
+}
+
+/// Value of `flag` given as `flag=value`, the only form start requests allow
+fn arg_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter().find_map(|arg| {
+        let value = arg.strip_prefix(flag)?.strip_prefix('=')?;
+        Some(value.to_string())
+    })
+}
+
+/// Scalar `key: value` at the top level of a YAML document. Only the plain
+/// and quoted forms mihomo configs use in practice are understood.
+fn top_level_value(yaml: &str, key: &str) -> Option<String> {
+    yaml.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.trim_start().strip_prefix(':')?;
+        let value = match value.find(" #") {
+            Some(comment) => &value[..comment],
+            None => value,
+        };
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        (!value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// Where to connect for a listen address: wildcard hosts mean loopback
+fn connect_addr(listen: &str) -> Option<String> {
+    let (host, port) = listen.rsplit_once(':')?;
+    port.parse::<u16>().ok()?;
+    let host = match host {
+        "" | "0.0.0.0" | "*" => "127.0.0.1",
+        "[::]" => "[::1]",
+        host => host,
+    };
+    Some(format!("{}:{}", host, port))
+}
@@ -1,23 +1,76 @@
 use super::{
-    data::{ClashStatus, CoreManager, MihomoStatus, StartBody, StatusInner},
-    process,
+    config,
+    controller::Controller,
+    data::{
+        ClashStatus, ConfigTestResult, CoreManager, CoreStatus, ExitRecord, MihomoStatus,
+        ResourceLimits, StartBody, StatusInner,
+    },
+    error::ServiceError,
+    events::{CoreEvent, EventBus},
+    health, instances, limits,
+    logs::LogHub,
+    output::Fifos,
+    privileges::{self, CoreUser},
+    process::{self, ExitInfo, ProcessIdentity, SpawnOptions, Termination},
+    rotate::RotatingFile,
+    shutdown, state, supervisor, validate,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
 use std::{
-    collections::HashMap,
-    sync::{atomic::Ordering, Arc, Mutex},
+    collections::VecDeque,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tokio::{
+    sync::{broadcast, oneshot},
+    time::Instant,
+};
+
+/// Logged by mihomo for the controller and each inbound once it is bound
+const READY_LOG_MARKER: &str = "listening at";
+/// Lines of core output returned with a failed start
+const LOG_TAIL_LINES: usize = 20;
 
 impl CoreManager {
-    pub fn new() -> Self {
+    pub fn new(name: &str) -> Self {
         CoreManager {
+            name: name.to_string(),
+            state_file: instances::state_file(name),
             clash_status: StatusInner::new(ClashStatus::default()),
             mihomo_status: StatusInner::new(MihomoStatus::default()),
+            log_hub: Arc::new(LogHub::new()),
+            events: Arc::new(EventBus::new()),
+            op_lock: tokio::sync::Mutex::new(()),
         }
     }
 
-    pub fn test_config_file(&self) -> Result<(), String> {
+    /// Run the core's config test and remember the outcome for the status API
+    pub async fn test_config_file(&self) -> Result<(), ServiceError> {
+        let result = self.run_config_test().await;
+        let record = ConfigTestResult {
+            passed: result.is_ok(),
+            message: result.as_ref().err().map(|e| e.to_string()),
+            at: unix_time(SystemTime::now()),
+        };
+        *self
+            .clash_status
+            .inner
+            .lock()
+            .unwrap()
+            .last_config_test
+            .lock()
+            .unwrap() = Some(record);
+        result
+    }
+
+    async fn run_config_test(&self) -> Result<(), ServiceError> {
         let config = match self
             .clash_status
             .inner
@@ -29,23 +82,33 @@ impl CoreManager {
             .clone()
         {
             Some(config) => config,
-            None => return Err("Runtime config is not set".to_string()),
+            None => return Err(ServiceError::ConfigNotSet),
         };
 
-        let bin_path = config.bin_path.as_str();
-        let config_dir = config.config_dir.as_str();
-        let config_file = config.config_file.as_str();
-        let args = vec!["-d", config_dir, "-f", config_file, "-t"];
-
-        println!(
+        info!(
             "Testing config file with bin_path: {}, config_dir: {}, config_file: {}",
-            bin_path, config_dir, config_file
+            config.bin_path, config.config_dir, config.config_file
         );
 
-        let result = process::spawn_process_debug(bin_path, &args)
-            .map_err(|e| format!("Failed to execute config test: {}", e))?;
+        // 以内核的账户和限制运行，测试时下载的文件内核之后也能替换
+        let options = process::blocking({
+            let config = config.clone();
+            move || {
+                Ok(core_user(&config).map(|user| SpawnOptions {
+                    user,
+                    ..spawn_options(&config)
+                }))
+            }
+        })
+        .await??;
+        let mut args = vec!["-d", &config.config_dir, "-f", &config.config_file];
+        args.extend(config.args.iter().map(String::as_str));
+        args.push("-t");
+        let result = process::spawn_process_debug(&config.bin_path, &args, &options)
+            .await
+            .map_err(|e| ServiceError::spawn_failed(e, &config.bin_path))?;
 
-        let (_pid, output, _exit_code) = result;
+        let (_pid, output, exit_code) = result;
 
         let mut errors: Vec<String> = Vec::new();
         for line in output.lines() {
@@ -61,22 +124,33 @@ impl CoreManager {
         }
 
         if !errors.is_empty() {
-            return Err(errors.join("\n"));
+            return Err(ServiceError::ConfigInvalid(errors.join("\n")));
+        }
+        // 没有 msg= 行的失败，例如 panic 或未定义的参数，只能看退出码
+        if exit_code != 0 {
+            let lines: Vec<&str> = output.lines().collect();
+            let tail = &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..];
+            return Err(ServiceError::ConfigInvalid(format!(
+                "Config test exited with code {}\n{}",
+                exit_code,
+                tail.join("\n")
+            )));
         }
 
-        println!("Config test passed successfully");
+        info!("Config test passed successfully");
         Ok(())
     }
 }
 
 impl CoreManager {
-    pub fn get_version(&self) -> Result<HashMap<String, String>> {
+    pub fn get_version(&self) -> Result<serde_json::Value> {
         let current_pid = std::process::id() as i32;
-        println!("Current PID: {}", current_pid);
-        Ok(HashMap::from([
-            ("service".into(), "SsRapid Desktop Service".into()),
-            ("version".into(), env!("CARGO_PKG_VERSION").into()),
-        ]))
+        info!("Current PID: {}", current_pid);
+        Ok(serde_json::json!({
+            "service": "SsRapid Desktop Service",
+            "version": env!("CARGO_PKG_VERSION"),
+            "config": config::get(),
+        }))
     }
 
     pub fn get_clash_status(&self) -> Result<StartBody> {
@@ -95,8 +169,152 @@ impl CoreManager {
         Ok(runtime_config.as_ref().unwrap().clone())
     }
 
-    pub fn start_mihomo(&self) -> Result<()> {
-        println!("Starting mihomo with config");
+    pub fn is_core_running(&self) -> bool {
+        let mihomo_status = self.mihomo_status.inner.lock().unwrap();
+        mihomo_status.is_running.load(Ordering::Relaxed)
+            && mihomo_status.running_pid.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn get_core_status(&self) -> CoreStatus {
+        let running = self.is_core_running();
+        let pid = self
+            .mihomo_status
+            .inner
+            .lock()
+            .unwrap()
+            .running_pid
+            .load(Ordering::Relaxed);
+        let config = self
+            .clash_status
+            .inner
+            .lock()
+            .unwrap()
+            .runtime_config
+            .lock()
+            .unwrap()
+            .clone();
+        let restart_count = self
+            .mihomo_status
+            .inner
+            .lock()
+            .unwrap()
+            .restart_count
+            .load(Ordering::Relaxed);
+        let last_stop = *self
+            .mihomo_status
+            .inner
+            .lock()
+            .unwrap()
+            .last_stop
+            .lock()
+            .unwrap();
+        let (started_at, last_exit, limits, user, health) = {
+            let mihomo_status = self.mihomo_status.inner.lock().unwrap();
+            let started_at = *mihomo_status.started_at.lock().unwrap();
+            let last_exit = *mihomo_status.last_exit.lock().unwrap();
+            let limits = mihomo_status.limits.lock().unwrap().clone();
+            let user = mihomo_status.user.lock().unwrap().clone();
+            let health = mihomo_status.health.lock().unwrap().clone();
+            (started_at, last_exit, limits, user, health)
+        };
+        let started_at = started_at.filter(|_| running);
+        let last_config_test = self
+            .clash_status
+            .inner
+            .lock()
+            .unwrap()
+            .last_config_test
+            .lock()
+            .unwrap()
+            .clone();
+        CoreStatus {
+            running,
+            pid: if running { Some(pid) } else { None },
+            config,
+            started_at: started_at.map(unix_time),
+            uptime_secs: started_at
+                .and_then(|started_at| started_at.elapsed().ok())
+                .map(|uptime| uptime.as_secs()),
+            restart_count,
+            last_exit,
+            last_stop,
+            last_config_test,
+            limits: limits.filter(|_| running),
+            user: user.filter(|_| running),
+            health: health.filter(|_| running),
+        }
+    }
+
+    pub(super) fn generation(&self) -> u64 {
+        self.mihomo_status
+            .inner
+            .lock()
+            .unwrap()
+            .generation
+            .load(Ordering::Relaxed)
+    }
+
+    /// Record that the core of `generation` has exited on its own; false if
+    /// it was already stopped or replaced
+    pub(super) fn mark_exited(&self, generation: u64) -> bool {
+        let mihomo_status = self.mihomo_status.inner.lock().unwrap();
+        if mihomo_status.generation.load(Ordering::Relaxed) != generation {
+            return false;
+        }
+        mihomo_status.running_pid.store(-1, Ordering::Relaxed);
+        mihomo_status.is_running.store(false, Ordering::Relaxed);
+        *mihomo_status.process.lock().unwrap() = None;
+        drop(mihomo_status);
+        self.forget_core();
+        true
+    }
+
+    /// Remove the record of the core and its output FIFOs
+    fn forget_core(&self) {
+        state::remove(&self.state_file);
+        Fifos::new(&self.state_file).remove();
+    }
+
+    pub(super) fn stored_config(&self) -> Result<StartBody, ServiceError> {
+        self.clash_status
+            .inner
+            .lock()
+            .unwrap()
+            .runtime_config
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(ServiceError::ConfigNotSet)
+    }
+
+    /// Start the core with the stored config, refusing if it is already up
+    pub async fn start_core(&self) -> Result<(), ServiceError> {
+        let _op = self.op_lock.lock().await;
+        if self.is_core_running() {
+            return Err(ServiceError::AlreadyRunning);
+        }
+        self.start_clash_locked(self.stored_config()?).await
+    }
+
+    /// Restart the core with the stored config
+    pub async fn restart_core(&self) -> Result<(), ServiceError> {
+        let _op = self.op_lock.lock().await;
+        self.start_clash_locked(self.stored_config()?).await
+    }
+
+    pub async fn stop_core(&self) -> Result<(), ServiceError> {
+        let _op = self.op_lock.lock().await;
+        if !self.is_core_running() {
+            return Err(ServiceError::NotRunning);
+        }
+        self.stop_mihomo_locked().await?;
+        Ok(())
+    }
+
+    /// Spawn the core from the stored config and put it under supervision;
+    /// the caller must hold `op_lock`
+    pub(super) async fn start_mihomo_locked(&self) -> Result<(), ServiceError> {
+        info!("Starting mihomo with config");
 
         {
             let is_running_mihomo = self
@@ -115,9 +333,9 @@ impl CoreManager {
                 .load(Ordering::Relaxed);
 
             if is_running_mihomo && mihomo_running_pid > 0 {
-                println!("Mihomo is already running, stopping it first");
-                let _ = self.stop_mihomo();
-                println!("Mihomo stopped successfully");
+                info!("Mihomo is already running, stopping it first");
+                let _ = self.stop_mihomo_locked().await;
+                info!("Mihomo stopped successfully");
             }
         }
 
@@ -132,47 +350,184 @@ impl CoreManager {
                 .lock()
                 .unwrap()
                 .clone();
-            let config = config.ok_or(anyhow!("Runtime config is not set"))?;
-
-            let bin_path = config.bin_path.as_str();
-            let config_dir = config.config_dir.as_str();
-            let config_file = config.config_file.as_str();
-            let log_file = config.log_file.as_str();
-            let args = vec!["-d", config_dir, "-f", config_file];
+            let config = config.ok_or(ServiceError::ConfigNotSet)?;
 
-            println!(
+            info!(
                 "Starting mihomo with bin_path: {}, config_dir: {}, config_file: {}, log_file: {}",
-                bin_path, config_dir, config_file, log_file
+                config.bin_path, config.config_dir, config.config_file, config.log_file
+            );
+
+            let (on_exit, exited) = self.exit_handler();
+            let ((identity, options, limits), controller) = process::blocking({
+                let config = config.clone();
+                let hub = self.log_hub.clone();
+                let state_file = self.state_file.clone();
+                move || {
+                    let spawned = spawn_core(&config, &state_file, hub, on_exit);
+                    if let Ok((identity, _, _)) = &spawned {
+                        save_state(&state_file, &config, identity);
+                    }
+                    Ok(spawned.map(|spawned| (spawned, Controller::from_config(&config))))
+                }
+            })
+            .await??;
+            info!("Mihomo started with PID: {}", identity.pid);
+            self.track_core(
+                identity,
+                SystemTime::now(),
+                &options,
+                limits,
+                controller,
+                exited,
             );
+        }
 
-            // Open log file
-            let log = std::fs::File::create(log_file)
-                .with_context(|| format!("Failed to open log file: {}", log_file))?;
+        Ok(())
+    }
 
-            // Spawn process
-            let pid = process::spawn_process(bin_path, &args, log)?;
-            println!("Mihomo started with PID: {}", pid);
+    /// Exit callback for a core process: records the exit, emits `exited`
+    /// and wakes the supervisor through the returned receiver
+    fn exit_handler(
+        &self,
+    ) -> (
+        impl FnOnce(u32, ExitInfo) + Send + 'static,
+        oneshot::Receiver<ExitInfo>,
+    ) {
+        let events = self.events.clone();
+        let last_exit = self.mihomo_status.inner.lock().unwrap().last_exit.clone();
+        let (exited_tx, exited_rx) = oneshot::channel();
+        let on_exit = move |pid, exit: ExitInfo| {
+            *last_exit.lock().unwrap() = Some(ExitRecord {
+                pid,
+                code: exit.code,
+                signal: exit.signal,
+                at: unix_time(SystemTime::now()),
+            });
+            events.emit(CoreEvent::Exited {
+                pid,
+                code: exit.code,
+                signal: exit.signal,
+            });
+            let _ = exited_tx.send(exit);
+        };
+        (on_exit, exited_rx)
+    }
 
-            // Update mihomo status
-            self.mihomo_status
-                .inner
-                .lock()
-                .unwrap()
+    /// Mark `identity` as the running core and put it under supervision;
+    /// `limits` are the ones it actually got, `controller` is probed by the
+    /// health check
+    fn track_core(
+        &self,
+        identity: ProcessIdentity,
+        started_at: SystemTime,
+        options: &SpawnOptions,
+        limits: ResourceLimits,
+        controller: Option<Controller>,
+        exited: oneshot::Receiver<ExitInfo>,
+    ) {
+        let pid = identity.pid;
+        let generation = {
+            let mihomo_status = self.mihomo_status.inner.lock().unwrap();
+            *mihomo_status.limits.lock().unwrap() = Some(limits);
+            *mihomo_status.user.lock().unwrap() = options.user.as_ref().map(|u| u.name.clone());
+            *mihomo_status.health.lock().unwrap() = None;
+            *mihomo_status.process.lock().unwrap() = Some(identity);
+            *mihomo_status.started_at.lock().unwrap() = Some(started_at);
+            mihomo_status
                 .running_pid
                 .store(pid as i32, Ordering::Relaxed);
-            self.mihomo_status
-                .inner
-                .lock()
-                .unwrap()
-                .is_running
-                .store(true, Ordering::Relaxed);
-            println!("Mihomo started successfully with PID: {}", pid);
+            mihomo_status.is_running.store(true, Ordering::Relaxed);
+            let generation = next_generation();
+            mihomo_status
+                .generation
+                .store(generation, Ordering::Relaxed);
+            generation
+        };
+        supervisor::watch(&self.name, pid, generation, exited);
+        health::watch(self, pid, generation, controller);
+        info!("Mihomo started successfully with PID: {}", pid);
+        self.events.emit(CoreEvent::Running { pid });
+    }
+
+    /// Pick up the core recorded by a previous run of the service: adopt it
+    /// if it is still alive and its config still passes validation, stop it
+    /// otherwise. Called once before the API starts serving.
+    pub async fn recover(&self) {
+        let _op = self.op_lock.lock().await;
+        let path = &self.state_file;
+        let Some(core_state) = state::load(path) else {
+            return;
+        };
+        let identity = core_state.process;
+        if !process::blocking({
+            let identity = identity.clone();
+            move || Ok(identity.is_current())
+        })
+        .await
+        .unwrap_or(false)
+        {
+            info!("Recorded core {} is no longer running", identity.pid);
+            self.forget_core();
+            return;
         }
 
-        Ok(())
+        let recorded = core_state.config;
+        let fifos = Fifos::new(path);
+        let reattached = process::blocking(move || Ok(reattach(&recorded, &fifos)))
+            .await
+            .unwrap_or_else(|e| Err(ServiceError::from(e)));
+        let (config, (stdout, stderr), log) = match reattached {
+            Ok(reattached) => reattached,
+            Err(e) => {
+                warn!("Stopping stale core {}: {}", identity.pid, e);
+                let grace = Duration::from_millis(config::get().stop_grace_ms);
+                if let Err(e) =
+                    process::blocking(move || process::kill_process(&identity, grace)).await
+                {
+                    error!("Failed to stop stale core: {}", e);
+                }
+                self.forget_core();
+                return;
+            }
+        };
+
+        info!("Adopting running core {} from previous run", identity.pid);
+        {
+            let clash_status = self.clash_status.inner.lock().unwrap();
+            clash_status
+                .running_pid
+                .store(std::process::id() as i32, Ordering::Relaxed);
+            clash_status.is_running.store(true, Ordering::Relaxed);
+        }
+        let (options, limits, controller) = process::blocking({
+            let config = config.clone();
+            let pid = identity.pid;
+            move || {
+                let options = SpawnOptions {
+                    user: core_user(&config).ok().flatten(),
+                    ..spawn_options(&config)
+                };
+                let limits = limits::effective(pid, &options.limits);
+                Ok((options, limits, Controller::from_config(&config)))
+            }
+        })
+        .await
+        .unwrap_or_default();
+        self.clash_status.inner.lock().unwrap().runtime_config = Arc::new(Mutex::new(Some(config)));
+        let started_at = identity.started_at().unwrap_or_else(SystemTime::now);
+        let (on_exit, exited) = self.exit_handler();
+        process::attach_output(stdout, stderr, log, self.log_hub.clone());
+        process::watch_adopted(identity.clone(), on_exit);
+        self.track_core(identity, started_at, &options, limits, controller, exited);
     }
 
-    pub fn stop_mihomo(&self) -> Result<()> {
+    /// Stop the core, returning how it ended or `None` if it was not running
+    pub async fn stop_mihomo(&self) -> Result<Option<Termination>> {
+        let _op = self.op_lock.lock().await;
+        self.stop_mihomo_locked().await
+    }
+
+    pub(super) async fn stop_mihomo_locked(&self) -> Result<Option<Termination>> {
         let mihomo_pid = self
             .mihomo_status
             .inner
@@ -181,39 +536,92 @@ impl CoreManager {
             .running_pid
             .load(Ordering::Relaxed);
         if mihomo_pid <= 0 {
-            println!("No running mihomo process found");
-            return Ok(());
+            info!("No running mihomo process found");
+            return Ok(None);
         }
-        println!("Stopping mihomo process {}", mihomo_pid);
+        info!("Stopping mihomo process {}", mihomo_pid);
+        // 先让监督任务知道这是主动停止，不要重启
+        self.mihomo_status
+            .inner
+            .lock()
+            .unwrap()
+            .generation
+            .store(next_generation(), Ordering::Relaxed);
 
-        let result = super::process::kill_process(mihomo_pid as u32)
+        let grace = Duration::from_millis(config::get().stop_grace_ms);
+        let identity = self
+            .mihomo_status
+            .inner
+            .lock()
+            .unwrap()
+            .process
+            .lock()
+            .unwrap()
+            .take();
+        let Some(identity) = identity else {
+            error!("No identity recorded for mihomo process {}", mihomo_pid);
+            self.reset_mihomo_status();
+            return Ok(None);
+        };
+        let result = process::blocking(move || process::kill_process(&identity, grace))
+            .await
             .with_context(|| format!("Failed to kill mihomo process with PID: {}", mihomo_pid));
 
-        match result {
-            Ok(_) => {
-                println!("Mihomo process {} stopped successfully", mihomo_pid);
+        match &result {
+            Ok(termination) => {
+                info!(
+                    "Mihomo process {} stopped successfully ({})",
+                    mihomo_pid,
+                    termination.signal.unwrap_or("already exited")
+                );
+                self.events.emit(CoreEvent::Stopped {
+                    pid: mihomo_pid as u32,
+                    signal: termination.signal,
+                });
+                *self
+                    .mihomo_status
+                    .inner
+                    .lock()
+                    .unwrap()
+                    .last_stop
+                    .lock()
+                    .unwrap() = Some(*termination);
             }
             Err(e) => {
-                eprintln!("Error killing mihomo process: {}", e);
+                error!("Error killing mihomo process: {}", e);
             }
         }
 
-        self.mihomo_status
-            .inner
-            .lock()
-            .unwrap()
-            .running_pid
-            .store(-1, Ordering::Relaxed);
-        self.mihomo_status
-            .inner
-            .lock()
-            .unwrap()
-            .is_running
-            .store(false, Ordering::Relaxed);
-        Ok(())
+        self.reset_mihomo_status();
+        result.map(Some)
+    }
+
+    fn reset_mihomo_status(&self) {
+        self.forget_core();
+        let mihomo_status = self.mihomo_status.inner.lock().unwrap();
+        mihomo_status.running_pid.store(-1, Ordering::Relaxed);
+        mihomo_status.is_running.store(false, Ordering::Relaxed);
+    }
+
+    /// Stop the core before the service exits; waits for any in-flight
+    /// start/stop to finish first
+    pub async fn shutdown(&self) -> Result<()> {
+        let _op = self.op_lock.lock().await;
+        self.stop_mihomo_locked().await.map(|_| ())
+    }
+
+    pub async fn start_clash(&self, body: StartBody) -> Result<(), ServiceError> {
+        let _op = self.op_lock.lock().await;
+        self.start_clash_locked(body).await
     }
 
-    pub fn start_clash(&self, body: StartBody) -> Result<(), String> {
+    /// Body of `start_clash`; the caller must hold `op_lock`
+    async fn start_clash_locked(&self, body: StartBody) -> Result<(), ServiceError> {
+        // 校验会访问文件系统，不在异步工作线程上执行
+        let body =
+            process::blocking(move || Ok(validate::validate_start_body(&body, config::get())))
+                .await??;
+
         {
             // Check clash & stop if needed
             let is_running_clash = self
@@ -233,11 +641,11 @@ impl CoreManager {
             let current_pid = std::process::id() as i32;
 
             if is_running_clash && clash_running_pid == current_pid {
-                println!("Clash is already running with pid: {}", current_pid);
+                info!("Clash is already running with pid: {}", current_pid);
             }
             if !is_running_clash && clash_running_pid <= 0 {
                 let current_pid = std::process::id() as i32;
-                println!("Clash is start running with pid: {}", current_pid);
+                info!("Clash is start running with pid: {}", current_pid);
                 self.clash_status
                     .inner
                     .lock()
@@ -250,21 +658,37 @@ impl CoreManager {
                     .unwrap()
                     .is_running
                     .store(true, Ordering::Relaxed);
-                println!("done");
+                info!("done");
             }
         }
 
         {
-            println!("Setting clash runtime config with config: {:?}", body);
+            let claim = instances::claim(self, &body).await?;
+            info!("Setting clash runtime config with config: {:?}", body);
             self.clash_status.inner.lock().unwrap().runtime_config =
                 Arc::new(Mutex::new(Some(body.clone())));
-            println!("Testing config file");
-            self.test_config_file()?;
+            drop(claim);
+            // 通过接口重新启动时重置自动重启计数
+            {
+                let mihomo_status = self.mihomo_status.inner.lock().unwrap();
+                mihomo_status.restart_count.store(0, Ordering::Relaxed);
+                mihomo_status.crash_streak.store(0, Ordering::Relaxed);
+            }
+            info!("Testing config file");
+            self.events.emit(CoreEvent::Starting);
+            if let Err(e) = self.test_config_file().await {
+                self.events.emit(CoreEvent::ConfigTestFailed {
+                    message: e.to_string(),
+                });
+                return Err(e);
+            }
         }
 
+        // 启动前订阅日志，不会错过内核输出的监听行
+        let (_, logs) = self.log_hub.subscribe(0);
         {
             // Check mihomo & stop if needed
-            println!("Checking if mihomo is running before start clash");
+            info!("Checking if mihomo is running before start clash");
             let is_mihomo_running = self
                 .mihomo_status
                 .inner
@@ -281,20 +705,96 @@ impl CoreManager {
                 .load(Ordering::Relaxed);
 
             if is_mihomo_running && mihomo_running_pid > 0 {
-                println!("Mihomo is running, stopping it first");
-                let _ = self.stop_mihomo();
-                let _ = self.start_mihomo();
+                info!("Mihomo is running, stopping it first");
+                let _ = self.stop_mihomo_locked().await;
+                self.start_mihomo_locked().await?;
+                let pid = self
+                    .mihomo_status
+                    .inner
+                    .lock()
+                    .unwrap()
+                    .running_pid
+                    .load(Ordering::Relaxed);
+                self.events.emit(CoreEvent::Restarted { pid: pid as u32 });
             } else {
-                println!("Mihomo is not running, starting it");
-                let _ = self.start_mihomo();
+                info!("Mihomo is not running, starting it");
+                self.start_mihomo_locked().await?;
             }
         }
 
-        println!("Clash started successfully");
+        if let Err(e) = self.wait_ready(logs).await {
+            error!("Core did not become ready: {}", e);
+            // 停止未就绪的内核，同时让监督器忽略这次退出，不再自动重启
+            let _ = self.stop_mihomo_locked().await;
+            return Err(e);
+        }
+
+        info!("Clash started successfully");
         Ok(())
     }
 
-    pub fn stop_clash(&self) -> Result<()> {
+    /// Wait until the just started core answers on its external controller
+    /// or logs that it is listening. Fails if it exits first or
+    /// `ready_timeout_ms` runs out; the caller must hold `op_lock`.
+    async fn wait_ready(&self, mut logs: broadcast::Receiver<String>) -> Result<(), ServiceError> {
+        let timeout = Duration::from_millis(config::get().ready_timeout_ms);
+        if timeout.is_zero() {
+            return Ok(());
+        }
+        let config = self.stored_config()?;
+        let controller = process::blocking(move || Ok(Controller::from_config(&config))).await?;
+        let (pid, last_exit) = {
+            let mihomo_status = self.mihomo_status.inner.lock().unwrap();
+            (
+                mihomo_status.running_pid.load(Ordering::Relaxed) as u32,
+                mihomo_status.last_exit.clone(),
+            )
+        };
+        let deadline = Instant::now() + timeout;
+        // 只收集本次启动后的输出，不混入上一个内核的日志
+        let mut tail = VecDeque::with_capacity(LOG_TAIL_LINES);
+
+        loop {
+            let exit = *last_exit.lock().unwrap();
+            if let Some(exit) = exit.filter(|exit| exit.pid == pid) {
+                // 给输出转发线程一点时间把最后几行转发过来
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                read_startup_logs(&mut logs, &mut tail);
+                let status = match (exit.code, exit.signal) {
+                    (Some(code), _) => format!("exit code {}", code),
+                    (_, Some(signal)) => format!("signal {}", signal),
+                    _ => "unknown status".to_string(),
+                };
+                return Err(ServiceError::CoreExited {
+                    message: format!("Core exited during startup with {}", status),
+                    log_tail: tail.into(),
+                });
+            }
+            if let Some(controller) = &controller {
+                if controller
+                    .get("/version", Duration::from_millis(500))
+                    .await
+                    .is_ok()
+                {
+                    info!("Core controller is answering");
+                    return Ok(());
+                }
+            }
+            if let Some(line) = read_startup_logs(&mut logs, &mut tail) {
+                info!("Core is ready: {}", line);
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(ServiceError::NotReady {
+                    message: format!("Core was not ready after {:?}", timeout),
+                    log_tail: tail.into(),
+                });
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    pub async fn stop_clash(&self) -> Result<()> {
         let clash_pid = self
             .clash_status
             .inner
@@ -303,45 +803,291 @@ impl CoreManager {
             .running_pid
             .load(Ordering::Relaxed);
         if clash_pid <= 0 {
-            println!("No running clash process found");
+            info!("No running clash process found");
             return Ok(());
         }
-        println!("Stopping clash process {}", clash_pid);
+        info!("Stopping clash process {}", clash_pid);
 
-        if let Err(e) = super::process::kill_process(clash_pid as u32)
-            .with_context(|| format!("Failed to kill clash process with PID: {}", clash_pid))
-        {
-            eprintln!("Error killing clash process: {}", e);
+        // clash pid 记录的是服务自身，走正常退出流程以便先停止内核
+        if clash_pid != std::process::id() as i32 {
+            // 没有记录其身份的 pid 可能已被复用，不能发送信号
+            error!("Refusing to signal unknown clash process {}", clash_pid);
+            return Ok(());
         }
-
-        println!("Clash process {} stopped successfully", clash_pid);
+        shutdown::request();
         Ok(())
     }
 }
 
-// 全局静态的 CoreManager 实例
-pub static COREMANAGER: Lazy<Arc<Mutex<CoreManager>>> =
-    Lazy::new(|| Arc::new(Mutex::new(CoreManager::new())));
-
-// 在服务启动时调用这个函数
-#[cfg(target_os = "linux")]
-pub fn init_signal_handler() {
-    use nix::sys::signal::{self, SigAction, SigHandler, Signal};
-    unsafe {
-        // 设置 SIGCHLD 处理函数，自动回收子进程
-        let sa = SigAction::new(
-            SigHandler::Handler(handle_sigchld),
-            signal::SaFlags::empty(),
-            signal::SigSet::empty(),
-        );
-        let _ = signal::sigaction(Signal::SIGCHLD, &sa);
+/// Move the lines received so far into `tail`, keeping the last
+/// `LOG_TAIL_LINES`; returns the first one that signals readiness
+fn read_startup_logs(
+    logs: &mut broadcast::Receiver<String>,
+    tail: &mut VecDeque<String>,
+) -> Option<String> {
+    loop {
+        match logs.try_recv() {
+            Ok(line) => {
+                if tail.len() == LOG_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line.clone());
+                if line.contains(READY_LOG_MARKER) {
+                    return Some(line);
+                }
+            }
+            Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+            Err(_) => return None,
+        }
     }
 }
 
-#[cfg(target_os = "linux")]
-extern "C" fn handle_sigchld(_: i32) {
-    unsafe {
-        // 循环等待所有已终止的子进程
-        while libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) > 0 {}
+/// The blocking part of starting the core: open its log, resolve the user,
+/// spawn it and read back the limits it got
+fn spawn_core(
+    config: &StartBody,
+    state_file: &Path,
+    hub: Arc<LogHub>,
+    on_exit: impl FnOnce(u32, ExitInfo) + Send + 'static,
+) -> Result<(ProcessIdentity, SpawnOptions, ResourceLimits), ServiceError> {
+    let log_file = config.log_file.as_str();
+    let log = RotatingFile::open(log_file, config::get().core_log_policy())
+        .with_context(|| format!("Failed to open log file: {}", log_file))?;
+    let options = SpawnOptions {
+        user: core_user(config)?,
+        ..spawn_options(config)
+    };
+    let mut args = vec![
+        "-d",
+        config.config_dir.as_str(),
+        "-f",
+        config.config_file.as_str(),
+    ];
+    args.extend(config.args.iter().map(String::as_str));
+    // 通过命名管道转发输出，服务重启后可以重新接上
+    let pipes = if cfg!(unix) {
+        let pipes = Fifos::new(state_file).create().map_err(|e| {
+            ServiceError::Internal(format!("Failed to create core output pipes: {}", e))
+        })?;
+        Some(pipes)
+    } else {
+        None
+    };
+    let identity =
+        process::spawn_process(&config.bin_path, &args, log, hub, pipes, &options, on_exit)
+            .map_err(|e| ServiceError::spawn_failed(e, &config.bin_path))?;
+    let limits = limits::effective(identity.pid, &options.limits);
+    Ok((identity, options, limits))
+}
+
+/// Check the config of a core found after a service restart and reopen its
+/// output and log; the core is not kept if any of this fails
+fn reattach(
+    recorded: &StartBody,
+    fifos: &Fifos,
+) -> Result<(StartBody, (File, File), RotatingFile), ServiceError> {
+    let config = validate::validate_start_body(recorded, config::get())?;
+    let output = fifos
+        .reopen()
+        .map_err(|e| ServiceError::Internal(format!("Cannot reattach core output: {}", e)))?;
+    let log = RotatingFile::append(&config.log_file, config::get().core_log_policy())
+        .with_context(|| format!("Failed to open log file: {}", config.log_file))?;
+    Ok((config, output, log))
+}
+
+/// Record the running core on disk so a restarted service can find it
+fn save_state(path: &Path, config: &StartBody, identity: &ProcessIdentity) {
+    let core_state = state::CoreState {
+        config: config.clone(),
+        process: identity.clone(),
+    };
+    if let Err(e) = state::save(path, &core_state) {
+        error!("Failed to save core state: {:#}", e);
+    }
+}
+
+/// Everything about the spawn that comes from `config` except the user,
+/// which has to be resolved
+fn spawn_options(config: &StartBody) -> SpawnOptions {
+    SpawnOptions {
+        limits: core_limits(config),
+        user: None,
+        env: config.env.clone(),
+        working_dir: config.working_dir.as_ref().map(PathBuf::from),
+    }
+}
+
+/// Limits for a core started with `config`: the request's own, falling back
+/// to the service defaults
+fn core_limits(config: &StartBody) -> ResourceLimits {
+    config
+        .limits
+        .clone()
+        .unwrap_or_default()
+        .or(&config::get().core_limits)
+}
+
+/// Account for a core started with `config`: the requested user (already
+/// checked against `allowed_core_users`) or the service's `core_user`
+fn core_user(config: &StartBody) -> Result<Option<CoreUser>, ServiceError> {
+    let service_config = config::get();
+    let Some(name) = config.user.as_ref().or(service_config.core_user.as_ref()) else {
+        return Ok(None);
+    };
+    privileges::resolve(name, service_config.core_group.as_deref())
+        .map(Some)
+        .map_err(ServiceError::from)
+}
+
+/// Seconds since the Unix epoch, as reported by the status API
+pub(super) fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+// 全局静态的 CoreManager 实例，内部状态自带锁，只读查询无需排队
+// 即默认实例，旧接口和 /v2/core 都操作它，其他实例见 instances
+pub static COREMANAGER: Lazy<Arc<CoreManager>> =
+    Lazy::new(|| Arc::new(CoreManager::new(instances::DEFAULT_INSTANCE)));
+
+/// Generations are unique across instances, so a supervisor that outlived
+/// its instance can never mistake a new core for its own
+fn next_generation() -> u64 {
+    static GENERATION: AtomicU64 = AtomicU64::new(0);
+    GENERATION.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    /// Points the service run by `old_service` at the test's directory
+    const DIR_VAR: &str = "SSRAPID_ADOPT_TEST_DIR";
+
+    /// Ready at once, then one line every 100 ms
+    const FAKE_CORE: &str = "#!/bin/sh
+echo 'listening at 127.0.0.1:0'
+i=0
+while true; do i=$((i + 1)); echo \"tick $i\"; sleep 0.1; done
+";
+
+    fn manager(dir: &Path) -> CoreManager {
+        CoreManager {
+            // 目录由启动内核时创建
+            state_file: dir.join("run").join("core.json"),
+            ..CoreManager::new("adopt-test")
+        }
+    }
+
+    fn body(dir: &Path) -> StartBody {
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        StartBody {
+            bin_path: path("mihomo"),
+            config_dir: path(""),
+            config_file: path("config.yaml"),
+            log_file: path("core.log"),
+            ..StartBody::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn config_test_fails_on_nonzero_exit() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().canonicalize().unwrap();
+        let core = dir.join("mihomo");
+        fs::write(
+            &core,
+            "#!/bin/sh\necho 'flag provided but not defined: -x' >&2\nexit 2\n",
+        )
+        .unwrap();
+        fs::set_permissions(&core, fs::Permissions::from_mode(0o755)).unwrap();
+        let manager = manager(&dir);
+        manager.clash_status.inner.lock().unwrap().runtime_config =
+            Arc::new(Mutex::new(Some(body(&dir))));
+
+        let err = manager.test_config_file().await.unwrap_err();
+        assert_eq!(err.code(), "config_invalid");
+        let message = "Config test exited with code 2\nflag provided but not defined: -x";
+        assert_eq!(err.to_string(), message);
+        let record = manager.get_core_status().last_config_test.unwrap();
+        assert!(!record.passed);
+        assert_eq!(record.message.as_deref(), Some(message));
+    }
+
+    #[tokio::test]
+    async fn config_test_runs_as_core_user() {
+        // 只有 root 能切换账户
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().canonicalize().unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let core = dir.join("mihomo");
+        fs::write(&core, "#!/bin/sh\nid -un\nexit 1\n").unwrap();
+        fs::set_permissions(&core, fs::Permissions::from_mode(0o755)).unwrap();
+        let manager = manager(&dir);
+        manager.clash_status.inner.lock().unwrap().runtime_config =
+            Arc::new(Mutex::new(Some(StartBody {
+                user: Some("nobody".into()),
+                ..body(&dir)
+            })));
+
+        let err = manager.test_config_file().await.unwrap_err();
+        assert_eq!(err.to_string(), "Config test exited with code 1\nnobody");
+    }
+
+    /// The service before the restart: start the core, then exit without
+    /// stopping it
+    #[tokio::test]
+    #[ignore = "run by adopted_core_keeps_logging in its own process"]
+    async fn old_service() {
+        let dir = PathBuf::from(std::env::var_os(DIR_VAR).unwrap());
+        let manager = manager(&dir);
+        manager.clash_status.inner.lock().unwrap().runtime_config =
+            Arc::new(Mutex::new(Some(body(&dir))));
+        let _op = manager.op_lock.lock().await;
+        manager.start_mihomo_locked().await.unwrap();
+        std::process::exit(0);
+    }
+
+    #[tokio::test]
+    async fn adopted_core_keeps_logging() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().canonicalize().unwrap();
+        let core = dir.join("mihomo");
+        fs::write(&core, FAKE_CORE).unwrap();
+        fs::set_permissions(&core, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(dir.join("config.yaml"), "mixed-port: 7890\n").unwrap();
+
+        let test = module_path!().split_once("::").unwrap().1;
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", &format!("{}::old_service", test), "--ignored"])
+            .env(DIR_VAR, &dir)
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        // 让内核在没有服务读取时继续写一会儿
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let manager = manager(&dir);
+        manager.recover().await;
+        assert!(manager.is_core_running());
+        let (_, mut logs) = manager.log_hub.subscribe(0);
+        let logged = fs::metadata(dir.join("core.log")).unwrap().len();
+        let line = tokio::time::timeout(Duration::from_secs(5), logs.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(line.starts_with("tick"), "unexpected line {:?}", line);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(fs::metadata(dir.join("core.log")).unwrap().len() > logged);
+
+        let termination = manager.stop_mihomo().await.unwrap();
+        assert!(termination.is_some());
+        assert!(!manager.is_core_running());
     }
 }
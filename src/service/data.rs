@@ -1,7 +1,17 @@
+use super::{
+    events::EventBus,
+    logs::LogHub,
+    process::{ProcessIdentity, Termination},
+};
 use serde::{Deserialize, Serialize};
-use std::sync::{
-    atomic::{AtomicBool, AtomicI32},
-    Arc, Mutex,
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64},
+        Arc, Mutex,
+    },
+    time::SystemTime,
 };
 
 #[derive(Default, Debug, Deserialize, Serialize, Clone)]
@@ -11,6 +21,119 @@ pub struct StartBody {
     pub config_dir: String,
     pub config_file: String,
     pub log_file: String,
+    /// Overrides the service's `core_limits` field by field
+    pub limits: Option<ResourceLimits>,
+    /// Run the core as this user instead of `core_user`; must be listed in
+    /// `allowed_core_users`
+    pub user: Option<String>,
+    /// Appended after `-d`/`-f`; every flag must be in `allowed_core_args`
+    /// and values are given as `-flag=value`
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Set on top of the service's environment; names must be in
+    /// `allowed_core_env`
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Defaults to the service's working directory
+    pub working_dir: Option<String>,
+}
+
+/// Limits applied to the core before it starts; unset fields are inherited
+/// from the service
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// `RLIMIT_NOFILE`, soft and hard
+    pub nofile: Option<u64>,
+    /// `RLIMIT_AS` in bytes
+    pub address_space: Option<u64>,
+    pub nice: Option<i32>,
+    /// CPUs the core may run on (Linux only)
+    pub cpu_affinity: Option<Vec<usize>>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.nofile.is_none()
+            && self.address_space.is_none()
+            && self.nice.is_none()
+            && self.cpu_affinity.is_none()
+    }
+
+    /// These limits with unset fields taken from `defaults`
+    pub fn or(&self, defaults: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            nofile: self.nofile.or(defaults.nofile),
+            address_space: self.address_space.or(defaults.address_space),
+            nice: self.nice.or(defaults.nice),
+            cpu_affinity: self
+                .cpu_affinity
+                .clone()
+                .or_else(|| defaults.cpu_affinity.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CoreStatus {
+    pub running: bool,
+    pub pid: Option<i32>,
+    pub config: Option<StartBody>,
+    /// Unix time the running core was spawned
+    pub started_at: Option<u64>,
+    pub uptime_secs: Option<u64>,
+    /// Crash restarts since the core was last started through the API
+    pub restart_count: u32,
+    /// How the previous core process ended, whether stopped or crashed
+    pub last_exit: Option<ExitRecord>,
+    /// How the core was last stopped through the API
+    pub last_stop: Option<Termination>,
+    pub last_config_test: Option<ConfigTestResult>,
+    /// Limits the running core is subject to
+    pub limits: Option<ResourceLimits>,
+    /// Account the running core runs as; `None` means the service's own
+    pub user: Option<String>,
+    /// Controller probe results; `None` when health checks are off
+    pub health: Option<HealthStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthStatus {
+    /// False once `health_check_failures` probes in a row have failed
+    pub healthy: bool,
+    /// Failed probes since the last successful one
+    pub failures: u32,
+    /// Unix time of the last probe
+    pub last_check: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl Default for HealthStatus {
+    fn default() -> Self {
+        HealthStatus {
+            healthy: true,
+            failures: 0,
+            last_check: None,
+            last_error: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ExitRecord {
+    pub pid: u32,
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    /// Unix time the exit was observed
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigTestResult {
+    pub passed: bool,
+    pub message: Option<String>,
+    /// Unix time the test finished
+    pub at: u64,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -25,17 +148,45 @@ pub struct ClashStatus {
     pub is_running: Arc<AtomicBool>,
     pub running_pid: Arc<AtomicI32>,
     pub runtime_config: Arc<Mutex<Option<StartBody>>>,
+    pub last_config_test: Arc<Mutex<Option<ConfigTestResult>>>,
 }
 
 #[derive(Default, Debug)]
 pub struct MihomoStatus {
     pub is_running: Arc<AtomicBool>,
     pub running_pid: Arc<AtomicI32>,
+    pub restart_count: Arc<AtomicU32>,
+    /// Crashes since the core last stayed up for a while, drives the backoff
+    pub crash_streak: Arc<AtomicU32>,
+    /// Bumped on every spawn and deliberate stop, so a supervisor can tell
+    /// whether the exit it saw is still the current core
+    pub generation: Arc<AtomicU64>,
+    pub last_stop: Arc<Mutex<Option<Termination>>>,
+    pub limits: Arc<Mutex<Option<ResourceLimits>>>,
+    pub user: Arc<Mutex<Option<String>>>,
+    pub health: Arc<Mutex<Option<HealthStatus>>>,
+    /// Identity of the running core, checked before it is signalled
+    pub process: Arc<Mutex<Option<ProcessIdentity>>>,
+    pub started_at: Arc<Mutex<Option<SystemTime>>>,
+    pub last_exit: Arc<Mutex<Option<ExitRecord>>>,
 }
 
+/// One named core instance with its own config, process, logs and events
 pub struct CoreManager {
+    pub name: String,
+    /// Where the running core is recorded, see `instances::state_file`
+    pub state_file: PathBuf,
     pub clash_status: StatusInner<ClashStatus>,
     pub mihomo_status: StatusInner<MihomoStatus>,
+    pub log_hub: Arc<LogHub>,
+    pub events: Arc<EventBus>,
+    /// Serializes start/stop operations; status queries never take it
+    pub op_lock: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogQuery {
+    pub backlog: Option<usize>,
 }
 
 pub struct StatusInner<T> {
@@ -0,0 +1,236 @@
+use super::{auth, guard};
+use log::warn;
+use serde::Serialize;
+use std::fmt;
+use warp::{http::StatusCode, Rejection};
+
+/// Errors returned by the API, each with a stable machine-readable code
+#[derive(Debug)]
+pub enum ServiceError {
+    Validation(Vec<FieldError>),
+    ConfigInvalid(String),
+    ConfigNotSet,
+    BinaryNotFound(String),
+    PathNotAllowed(String),
+    AlreadyRunning,
+    NotRunning,
+    /// The core exited before it became ready
+    CoreExited {
+        message: String,
+        log_tail: Vec<String>,
+    },
+    /// The core was still not ready when `ready_timeout_ms` ran out
+    NotReady {
+        message: String,
+        log_tail: Vec<String>,
+    },
+    BadRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    UnsupportedMediaType,
+    PayloadTooLarge,
+    LengthRequired,
+    NotFound,
+    InstanceNotFound(String),
+    /// Another instance uses the log file or controller address
+    InUse(String),
+    MethodNotAllowed,
+    Internal(String),
+}
+
+/// A rejected request field and why
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    /// Last lines of the core's output for start failures
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub log_tail: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+impl ServiceError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServiceError::Validation(_) => "validation_failed",
+            ServiceError::ConfigInvalid(_) => "config_invalid",
+            ServiceError::ConfigNotSet => "config_not_set",
+            ServiceError::BinaryNotFound(_) => "binary_not_found",
+            ServiceError::PathNotAllowed(_) => "path_not_allowed",
+            ServiceError::AlreadyRunning => "already_running",
+            ServiceError::NotRunning => "not_running",
+            ServiceError::CoreExited { .. } => "core_exited",
+            ServiceError::NotReady { .. } => "core_not_ready",
+            ServiceError::BadRequest(_) => "bad_request",
+            ServiceError::Unauthorized => "unauthorized",
+            ServiceError::Forbidden(_) => "forbidden",
+            ServiceError::UnsupportedMediaType => "unsupported_media_type",
+            ServiceError::PayloadTooLarge => "payload_too_large",
+            ServiceError::LengthRequired => "length_required",
+            ServiceError::NotFound => "not_found",
+            ServiceError::InstanceNotFound(_) => "instance_not_found",
+            ServiceError::InUse(_) => "in_use",
+            ServiceError::MethodNotAllowed => "method_not_allowed",
+            ServiceError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServiceError::ConfigInvalid(_) | ServiceError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServiceError::Validation(_)
+            | ServiceError::BinaryNotFound(_)
+            | ServiceError::CoreExited { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ServiceError::NotReady { .. } => StatusCode::GATEWAY_TIMEOUT,
+            ServiceError::PathNotAllowed(_) | ServiceError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServiceError::ConfigNotSet
+            | ServiceError::AlreadyRunning
+            | ServiceError::NotRunning
+            | ServiceError::InUse(_) => StatusCode::CONFLICT,
+            ServiceError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ServiceError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ServiceError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ServiceError::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            ServiceError::NotFound | ServiceError::InstanceNotFound(_) => StatusCode::NOT_FOUND,
+            ServiceError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ServiceError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn to_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorBody {
+                code: self.code(),
+                // 日志尾部单独放在 log_tail 中
+                message: match self {
+                    ServiceError::CoreExited { message, .. }
+                    | ServiceError::NotReady { message, .. } => message.clone(),
+                    _ => self.to_string(),
+                },
+                fields: match self {
+                    ServiceError::Validation(fields) => fields.clone(),
+                    _ => Vec::new(),
+                },
+                log_tail: self.log_tail().to_vec(),
+            },
+        }
+    }
+
+    pub fn log_tail(&self) -> &[String] {
+        match self {
+            ServiceError::CoreExited { log_tail, .. } | ServiceError::NotReady { log_tail, .. } => {
+                log_tail
+            }
+            _ => &[],
+        }
+    }
+
+    /// Classify a failure to launch the core binary
+    pub fn spawn_failed(err: std::io::Error, bin_path: &str) -> ServiceError {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => ServiceError::BinaryNotFound(bin_path.to_string()),
+            _ => ServiceError::Internal(format!("Failed to execute {}: {}", bin_path, err)),
+        }
+    }
+
+    /// Map a filter rejection (auth, guard, routing, body parsing) to an error
+    pub fn from_rejection(err: &Rejection) -> ServiceError {
+        if err.find::<auth::Unauthorized>().is_some() {
+            ServiceError::Unauthorized
+        } else if err.find::<guard::ForbiddenHost>().is_some() {
+            ServiceError::Forbidden("host not allowed".into())
+        } else if err.find::<guard::ForbiddenOrigin>().is_some() {
+            ServiceError::Forbidden("cross-origin requests are not allowed".into())
+        } else if err.find::<guard::UnsupportedContentType>().is_some()
+            || err.find::<warp::reject::UnsupportedMediaType>().is_some()
+        {
+            ServiceError::UnsupportedMediaType
+        } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+            ServiceError::PayloadTooLarge
+        } else if err.find::<warp::reject::LengthRequired>().is_some() {
+            ServiceError::LengthRequired
+        } else if err.is_not_found() {
+            ServiceError::NotFound
+        } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+            ServiceError::BadRequest(e.to_string())
+        } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+            ServiceError::MethodNotAllowed
+        } else {
+            // warp 的内部细节只写入日志，不返回给客户端
+            warn!("Rejected request: {:?}", err);
+            ServiceError::BadRequest("bad request".into())
+        }
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::Validation(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|e| format!("{}: {}", e.field, e.message))
+                    .collect();
+                write!(f, "Invalid start request: {}", fields.join("; "))
+            }
+            ServiceError::ConfigInvalid(msg) => write!(f, "{}", msg),
+            ServiceError::ConfigNotSet => write!(f, "Runtime config is not set"),
+            ServiceError::BinaryNotFound(path) => write!(f, "Core binary not found: {}", path),
+            ServiceError::PathNotAllowed(path) => write!(
+                f,
+                "Core binary is outside the allowed core directories: {}",
+                path
+            ),
+            ServiceError::AlreadyRunning => write!(f, "Core is already running"),
+            ServiceError::NotRunning => write!(f, "Core is not running"),
+            // 旧接口只有 msg 字段，把日志尾部一并带上
+            ServiceError::CoreExited { message, log_tail }
+            | ServiceError::NotReady { message, log_tail } => {
+                write!(f, "{}", message)?;
+                if !log_tail.is_empty() {
+                    write!(f, "\n{}", log_tail.join("\n"))?;
+                }
+                Ok(())
+            }
+            ServiceError::BadRequest(msg) => write!(f, "{}", msg),
+            ServiceError::Unauthorized => write!(f, "unauthorized"),
+            ServiceError::Forbidden(msg) => write!(f, "{}", msg),
+            ServiceError::UnsupportedMediaType => {
+                write!(f, "content type must be application/json")
+            }
+            ServiceError::PayloadTooLarge => write!(f, "request body is too large"),
+            ServiceError::LengthRequired => write!(f, "content-length is required"),
+            ServiceError::NotFound => write!(f, "not found"),
+            ServiceError::InstanceNotFound(name) => write!(f, "No core instance named {}", name),
+            ServiceError::InUse(msg) => write!(f, "{}", msg),
+            ServiceError::MethodNotAllowed => write!(f, "method not allowed"),
+            ServiceError::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+impl From<anyhow::Error> for ServiceError {
+    fn from(err: anyhow::Error) -> Self {
+        ServiceError::Internal(format!("{:#}", err))
+    }
+}
+
+impl From<std::io::Error> for ServiceError {
+    fn from(err: std::io::Error) -> Self {
+        ServiceError::Internal(err.to_string())
+    }
+}
@@ -0,0 +1,106 @@
+use super::shutdown;
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use warp::{
+    ws::{Message, WebSocket, Ws},
+    Reply,
+};
+
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Core lifecycle changes pushed to clients, serialized as
+/// `{"event": "running", "pid": 1234}` and so on.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum CoreEvent {
+    Starting,
+    ConfigTestFailed {
+        message: String,
+    },
+    Running {
+        pid: u32,
+    },
+    /// Follows `running` when a start replaced an already running core
+    Restarted {
+        pid: u32,
+    },
+    Stopped {
+        pid: u32,
+        /// `SIGTERM` or `SIGKILL`, see `process::kill_process`
+        signal: Option<&'static str>,
+    },
+    Exited {
+        pid: u32,
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    /// The core crashed and will be started again after `delay_ms`
+    RestartScheduled {
+        attempt: u32,
+        delay_ms: u64,
+    },
+    /// The controller stopped answering health checks
+    Unhealthy {
+        pid: u32,
+        failures: u32,
+    },
+    /// The core kept crashing and the supervisor stopped restarting it
+    RestartsExhausted {
+        restarts: u32,
+    },
+}
+
+pub struct EventBus {
+    sender: broadcast::Sender<CoreEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        EventBus { sender }
+    }
+
+    pub fn emit(&self, event: CoreEvent) {
+        log::debug!("Core event: {:?}", event);
+        // 没有订阅者时发送失败是正常的
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CoreEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Upgrade to a WebSocket that pushes every event as a JSON text frame
+pub fn ws_reply(ws: Ws, receiver: broadcast::Receiver<CoreEvent>) -> impl Reply {
+    ws.on_upgrade(move |socket| forward(socket, receiver))
+}
+
+async fn forward(socket: WebSocket, mut receiver: broadcast::Receiver<CoreEvent>) {
+    let (mut tx, mut rx) = socket.split();
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Ok(text) = serde_json::to_string(&event) else {
+                    continue;
+                };
+                if tx.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+            // 客户端只会发送 ping/close，断开时结束推送
+            message = rx.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => break,
+            },
+            _ = shutdown::requested() => break,
+        }
+    }
+    let _ = tx.close().await;
+}
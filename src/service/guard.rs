@@ -0,0 +1,225 @@
+use std::{net::IpAddr, sync::Arc};
+use warp::{http::Method, reject::Reject, Filter, Rejection};
+
+#[derive(Debug)]
+pub struct ForbiddenHost;
+
+impl Reject for ForbiddenHost {}
+
+#[derive(Debug)]
+pub struct ForbiddenOrigin;
+
+impl Reject for ForbiddenOrigin {}
+
+#[derive(Debug)]
+pub struct UnsupportedContentType;
+
+impl Reject for UnsupportedContentType {}
+
+/// Host header values accepted for a service listening on `addr:port`
+pub fn allowed_hosts(addr: IpAddr, port: u16) -> Vec<String> {
+    let mut hosts = vec![
+        "127.0.0.1".to_string(),
+        "localhost".to_string(),
+        "[::1]".to_string(),
+    ];
+    if !addr.is_unspecified() && !addr.is_loopback() {
+        hosts.push(match addr {
+            IpAddr::V4(addr) => addr.to_string(),
+            IpAddr::V6(addr) => format!("[{}]", addr),
+        });
+    }
+    hosts
+        .iter()
+        .flat_map(|host| [host.clone(), format!("{}:{}", host, port)])
+        .collect()
+}
+
+/// Only let through requests that cannot have come from a web page: the Host
+/// must be a loopback name (defeats DNS rebinding), no browser `Origin` may be
+/// present, and requests that change state must be sent as JSON, which a plain
+/// cross-origin form post cannot do.
+///
+/// `allowed_hosts` is `None` on the Unix socket: no web page can reach it, and
+/// clients there often send a Host that is not a loopback name, so only the
+/// JSON rule applies.
+pub fn local_request(
+    allowed_hosts: Option<Arc<Vec<String>>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and_then(
+            move |method: Method,
+                  host: Option<String>,
+                  origin: Option<String>,
+                  content_type: Option<String>| {
+                let allowed_hosts = allowed_hosts.clone();
+                async move {
+                    check_request(
+                        allowed_hosts.as_deref(),
+                        &method,
+                        host,
+                        origin,
+                        content_type,
+                    )
+                }
+            },
+        )
+        .untuple_one()
+}
+
+fn check_request(
+    allowed_hosts: Option<&Vec<String>>,
+    method: &Method,
+    host: Option<String>,
+    origin: Option<String>,
+    content_type: Option<String>,
+) -> Result<(), Rejection> {
+    if let Some(allowed_hosts) = allowed_hosts {
+        let host = host.map(|host| host.trim().to_ascii_lowercase());
+        if !host.is_some_and(|host| allowed_hosts.contains(&host)) {
+            return Err(warp::reject::custom(ForbiddenHost));
+        }
+
+        if origin.is_some() {
+            return Err(warp::reject::custom(ForbiddenOrigin));
+        }
+    }
+
+    if method != Method::GET && method != Method::HEAD {
+        let is_json = content_type
+            .as_deref()
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"));
+        if !is_json {
+            return Err(warp::reject::custom(UnsupportedContentType));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn guarded() -> impl Filter<Extract = (&'static str,), Error = Rejection> + Clone {
+        local_request(Some(Arc::new(allowed_hosts(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            33211,
+        ))))
+        .map(|| "ok")
+    }
+
+    #[tokio::test]
+    async fn accepts_local_json_request() {
+        let result = warp::test::request()
+            .method("POST")
+            .path("/start_clash")
+            .header("host", "127.0.0.1:33211")
+            .header("content-type", "application/json; charset=utf-8")
+            .filter(&guarded())
+            .await;
+        assert_eq!(result.unwrap(), "ok");
+
+        let result = warp::test::request()
+            .path("/version")
+            .header("host", "localhost")
+            .filter(&guarded())
+            .await;
+        assert_eq!(result.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn rejects_rebound_host() {
+        let rejection = warp::test::request()
+            .path("/version")
+            .header("host", "attacker.example:33211")
+            .filter(&guarded())
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<ForbiddenHost>().is_some());
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_host() {
+        let rejection = warp::test::request()
+            .path("/version")
+            .filter(&guarded())
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<ForbiddenHost>().is_some());
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_port() {
+        let rejection = warp::test::request()
+            .path("/version")
+            .header("host", "127.0.0.1:8080")
+            .filter(&guarded())
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<ForbiddenHost>().is_some());
+    }
+
+    #[tokio::test]
+    async fn rejects_browser_origin() {
+        let rejection = warp::test::request()
+            .method("POST")
+            .path("/start_clash")
+            .header("host", "127.0.0.1:33211")
+            .header("origin", "https://attacker.example")
+            .header("content-type", "application/json")
+            .filter(&guarded())
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<ForbiddenOrigin>().is_some());
+    }
+
+    #[tokio::test]
+    async fn rejects_form_post() {
+        let rejection = warp::test::request()
+            .method("POST")
+            .path("/start_clash")
+            .header("host", "127.0.0.1:33211")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .filter(&guarded())
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<UnsupportedContentType>().is_some());
+
+        let rejection = warp::test::request()
+            .method("POST")
+            .path("/stop_clash")
+            .header("host", "127.0.0.1:33211")
+            .filter(&guarded())
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<UnsupportedContentType>().is_some());
+    }
+
+    #[tokio::test]
+    async fn unix_socket_skips_host_and_origin() {
+        let unix = local_request(None).map(|| "ok");
+        let result = warp::test::request()
+            .method("POST")
+            .path("/start_clash")
+            .header("host", "2f72756e2f73737261706964")
+            .header("origin", "null")
+            .header("content-type", "application/json")
+            .filter(&unix)
+            .await;
+        assert_eq!(result.unwrap(), "ok");
+
+        let rejection = warp::test::request()
+            .method("POST")
+            .path("/stop_clash")
+            .filter(&unix)
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<UnsupportedContentType>().is_some());
+    }
+}
@@ -0,0 +1,139 @@
+use super::{
+    config,
+    controller::Controller,
+    core::unix_time,
+    data::{CoreManager, HealthStatus},
+    events::CoreEvent,
+    instances, shutdown,
+};
+use log::{error, info, warn};
+use std::{
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// Upper bound for a single probe, so a slow controller cannot stall the
+/// loop past its interval
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Probe `controller` of the core that `core` spawned as `pid` every
+/// `health_check_interval_secs` until it is stopped or replaced. Does
+/// nothing when health checks are off or the core has no TCP controller.
+/// The caller resolves `controller`, which may read the config file.
+pub fn watch(core: &CoreManager, pid: u32, generation: u64, controller: Option<Controller>) {
+    let config = config::get();
+    if config.health_check_interval_secs == 0 {
+        return;
+    }
+    let controller = match controller {
+        Some(controller) => controller,
+        None => {
+            warn!("Core has no external controller, health checks are disabled");
+            return;
+        }
+    };
+    let health = core.mihomo_status.inner.lock().unwrap().health.clone();
+    *health.lock().unwrap() = Some(HealthStatus::default());
+    let interval = Duration::from_secs(config.health_check_interval_secs);
+    tokio::spawn(probe_loop(
+        core.name.clone(),
+        pid,
+        generation,
+        controller,
+        health,
+        interval,
+    ));
+}
+
+async fn probe_loop(
+    name: String,
+    pid: u32,
+    generation: u64,
+    controller: Controller,
+    health: Arc<Mutex<Option<HealthStatus>>>,
+    interval: Duration,
+) {
+    let config = config::get();
+    let Some(core) = instances::get(&name) else {
+        return;
+    };
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown::requested() => return,
+        }
+        if core.generation() != generation {
+            return;
+        }
+
+        let result = controller
+            .get("/version", interval.min(PROBE_TIMEOUT))
+            .await;
+        let failures = {
+            let mut health = health.lock().unwrap();
+            // 内核在探测期间被替换时，状态已属于新的内核
+            if core.generation() != generation {
+                return;
+            }
+            let status = health.get_or_insert_with(HealthStatus::default);
+            status.last_check = Some(unix_time(SystemTime::now()));
+            match result {
+                Ok(_) => {
+                    if !status.healthy {
+                        info!("Core {} is answering again", pid);
+                    }
+                    status.healthy = true;
+                    status.failures = 0;
+                    status.last_error = None;
+                }
+                Err(e) => {
+                    status.failures += 1;
+                    status.last_error = Some(e.to_string());
+                    warn!(
+                        "Health check of core {} failed ({}/{}): {}",
+                        pid, status.failures, config.health_check_failures, e
+                    );
+                }
+            }
+            status.failures
+        };
+        if failures != config.health_check_failures {
+            continue;
+        }
+
+        error!("Core {} is not answering, marking it unhealthy", pid);
+        if let Some(status) = health.lock().unwrap().as_mut() {
+            status.healthy = false;
+        }
+        core.events.emit(CoreEvent::Unhealthy { pid, failures });
+        if config.health_check_restart {
+            restart(&core, generation).await;
+            return;
+        }
+    }
+}
+
+/// Replace the hung core; its successor gets its own health loop
+async fn restart(core: &CoreManager, generation: u64) {
+    let _op = core.op_lock.lock().await;
+    if core.generation() != generation {
+        return;
+    }
+    info!("Restarting unhealthy core");
+    if let Err(e) = core.stop_mihomo_locked().await {
+        error!("Failed to stop unhealthy core: {}", e);
+        return;
+    }
+    let (restart_count, running_pid) = {
+        let status = core.mihomo_status.inner.lock().unwrap();
+        (status.restart_count.clone(), status.running_pid.clone())
+    };
+    restart_count.fetch_add(1, Ordering::Relaxed);
+    match core.start_mihomo_locked().await {
+        Ok(_) => {
+            let pid = running_pid.load(Ordering::Relaxed) as u32;
+            core.events.emit(CoreEvent::Restarted { pid });
+        }
+        Err(e) => error!("Failed to restart unhealthy core: {}", e),
+    }
+}
@@ -0,0 +1,313 @@
+use super::{
+    config,
+    controller::Controller,
+    core::COREMANAGER,
+    data::{CoreManager, CoreStatus, StartBody},
+    error::ServiceError,
+    process, validate,
+};
+use anyhow::Result;
+use futures_util::future::join_all;
+use log::{error, info};
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::MutexGuard;
+
+#[cfg(test)]
+use std::cell::RefCell;
+
+/// Instance the legacy routes and `/v2/core` operate on; it always exists
+pub const DEFAULT_INSTANCE: &str = "default";
+const MAX_NAME_LEN: usize = 32;
+
+// 按名称索引的内核实例，默认实例即 COREMANAGER
+static INSTANCES: Lazy<Mutex<BTreeMap<String, Arc<CoreManager>>>> = Lazy::new(|| {
+    Mutex::new(BTreeMap::from([(
+        DEFAULT_INSTANCE.to_string(),
+        COREMANAGER.clone(),
+    )]))
+});
+
+#[cfg(test)]
+thread_local! {
+    /// Where instances created on this thread keep their state, so tests
+    /// stay out of the system's state directory
+    pub static STATE_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+// 检查占用和保存配置之间持有，避免两个实例同时占用同一日志文件
+static CLAIM: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+pub fn get(name: &str) -> Option<Arc<CoreManager>> {
+    INSTANCES.lock().unwrap().get(name).cloned()
+}
+
+/// The instance called `name`, or `InstanceNotFound`
+pub fn existing(name: &str) -> Result<Arc<CoreManager>, ServiceError> {
+    get(name).ok_or_else(|| ServiceError::InstanceNotFound(name.to_string()))
+}
+
+/// The instance called `name`, created empty if it does not exist yet
+pub fn get_or_create(name: &str) -> Result<Arc<CoreManager>, ServiceError> {
+    create(name).map(|(core, _)| core)
+}
+
+/// Start `body` on instance `name`, creating the instance if needed. The
+/// request is validated before anything is created, and an instance created
+/// here is removed again if its core does not start.
+pub async fn start(name: &str, body: StartBody) -> Result<Arc<CoreManager>, ServiceError> {
+    check_name(name)?;
+    let body = process::blocking(move || Ok(validate::validate_start_body(&body, config::get())))
+        .await??;
+    let (core, created) = create(name)?;
+    match core.start_clash(body).await {
+        Ok(()) => Ok(core),
+        Err(e) => {
+            if created {
+                discard(core).await;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// The instance called `name` and whether it was just created
+fn create(name: &str) -> Result<(Arc<CoreManager>, bool), ServiceError> {
+    check_name(name)?;
+    let mut instances = INSTANCES.lock().unwrap();
+    if let Some(core) = instances.get(name) {
+        return Ok((core.clone(), false));
+    }
+    info!("Creating core instance {}", name);
+    let core = Arc::new(CoreManager::new(name));
+    instances.insert(name.to_string(), core.clone());
+    Ok((core, true))
+}
+
+/// Forget an instance whose first start failed. A request that got hold of
+/// it in the meantime finds it gone once it gets to `claim`.
+async fn discard(core: Arc<CoreManager>) {
+    let _op = core.op_lock.lock().await;
+    let _claim = CLAIM.lock().await;
+    if core.is_core_running() {
+        return;
+    }
+    let mut instances = INSTANCES.lock().unwrap();
+    if instances
+        .get(&core.name)
+        .is_some_and(|listed| Arc::ptr_eq(listed, &core))
+    {
+        instances.remove(&core.name);
+        info!("Removed core instance {} after a failed start", core.name);
+    }
+}
+
+/// Check that `core` is still listed and that no other instance uses the
+/// log file or the controller address of `config`. The returned guard must
+/// be held until `config` is stored, so two starts cannot claim the same ones.
+pub async fn claim(
+    core: &CoreManager,
+    config: &StartBody,
+) -> Result<MutexGuard<'static, ()>, ServiceError> {
+    let guard = CLAIM.lock().await;
+    let name = &core.name;
+    if !get(name).is_some_and(|listed| std::ptr::eq(listed.as_ref(), core)) {
+        return Err(ServiceError::InstanceNotFound(name.clone()));
+    }
+    let others: Vec<(String, StartBody)> = all()
+        .into_iter()
+        .filter(|other| &other.name != name)
+        .filter_map(|other| Some((other.name.clone(), other.stored_config().ok()?)))
+        .collect();
+    let config = config.clone();
+    // 控制器地址可能要从配置文件中读取
+    process::blocking(move || Ok(check_claim(&config, &others))).await??;
+    Ok(guard)
+}
+
+fn check_claim(config: &StartBody, others: &[(String, StartBody)]) -> Result<(), ServiceError> {
+    let controller = Controller::from_config(config);
+    for (name, other) in others {
+        if other.log_file == config.log_file {
+            return Err(ServiceError::InUse(format!(
+                "Log file {} is already used by instance {}",
+                config.log_file, name
+            )));
+        }
+        if let Some(controller) = &controller {
+            if Controller::from_config(other).is_some_and(|other| other.addr() == controller.addr())
+            {
+                return Err(ServiceError::InUse(format!(
+                    "External controller {} is already used by instance {}",
+                    controller.addr(),
+                    name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Status of every instance by name
+pub fn statuses() -> BTreeMap<String, CoreStatus> {
+    all()
+        .into_iter()
+        .map(|core| (core.name.clone(), core.get_core_status()))
+        .collect()
+}
+
+/// Stop an instance and forget it; the default instance is only stopped
+pub async fn remove(name: &str) -> Result<(), ServiceError> {
+    let core = existing(name)?;
+    core.shutdown().await?;
+    if name != DEFAULT_INSTANCE {
+        INSTANCES.lock().unwrap().remove(name);
+        info!("Removed core instance {}", name);
+    }
+    Ok(())
+}
+
+/// Pick up the cores recorded by a previous run of the service, see
+/// `CoreManager::recover`
+pub async fn recover() {
+    COREMANAGER.recover().await;
+    for name in recorded_names() {
+        if let Ok(core) = get_or_create(&name) {
+            core.recover().await;
+            // 没有接管到内核的实例不保留
+            if !core.is_core_running() {
+                INSTANCES.lock().unwrap().remove(&name);
+            }
+        }
+    }
+}
+
+/// Stop every instance before the service exits, all at the same time so
+/// the grace periods do not add up
+pub async fn shutdown() -> Result<()> {
+    let cores = all();
+    let results = join_all(cores.iter().map(|core| core.shutdown())).await;
+    let mut result = Ok(());
+    for (core, stopped) in cores.iter().zip(results) {
+        if let Err(e) = stopped {
+            error!("Failed to stop core instance {}: {:#}", core.name, e);
+            result = Err(e);
+        }
+    }
+    result
+}
+
+fn all() -> Vec<Arc<CoreManager>> {
+    INSTANCES.lock().unwrap().values().cloned().collect()
+}
+
+fn check_name(name: &str) -> Result<(), ServiceError> {
+    if is_valid_name(name) {
+        return Ok(());
+    }
+    Err(ServiceError::BadRequest(format!(
+        "Instance names must be 1-{} letters, digits, '-' or '_': {}",
+        MAX_NAME_LEN, name
+    )))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Where the core of instance `name` is recorded: `state_file` for the
+/// default instance, `<stem>.<name>.<ext>` next to it for the others
+pub fn state_file(name: &str) -> PathBuf {
+    #[cfg(test)]
+    if let Some(dir) = STATE_DIR.with(|dir| dir.borrow().clone()) {
+        return dir.join(format!("core.{}.json", name));
+    }
+    let path = &config::get().state_file;
+    if name == DEFAULT_INSTANCE {
+        return path.clone();
+    }
+    let (stem, ext) = state_file_parts();
+    path.with_file_name(format!("{}.{}{}", stem, name, ext))
+}
+
+fn state_file_parts() -> (String, String) {
+    let path = &config::get().state_file;
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (stem, ext)
+}
+
+/// Names of the non-default instances that have a state file
+fn recorded_names() -> Vec<String> {
+    let path = &config::get().state_file;
+    let Some(Ok(entries)) = path.parent().map(std::fs::read_dir) else {
+        return Vec::new();
+    };
+    let (stem, ext) = state_file_parts();
+    let prefix = format!("{}.", stem);
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|file| {
+            let name = file.strip_prefix(&prefix)?.strip_suffix(&ext)?;
+            (is_valid_name(name) && name != DEFAULT_INSTANCE).then(|| name.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(log_file: &str, args: &[&str]) -> StartBody {
+        StartBody {
+            config_file: "/nonexistent/config.yaml".into(),
+            log_file: log_file.into(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            ..StartBody::default()
+        }
+    }
+
+    #[test]
+    fn claims_free_log_file_and_controller() {
+        let others = [("a".to_string(), body("/var/log/a.log", &["-ext-ctl=:9090"]))];
+        assert!(check_claim(&body("/var/log/b.log", &["-ext-ctl=:9091"]), &others).is_ok());
+        assert!(check_claim(&body("/var/log/b.log", &[]), &others).is_ok());
+    }
+
+    #[test]
+    fn rejects_log_file_of_other_instance() {
+        let others = [("a".to_string(), body("/var/log/core.log", &[]))];
+        let err = check_claim(&body("/var/log/core.log", &[]), &others).unwrap_err();
+        assert_eq!(err.code(), "in_use");
+        assert_eq!(
+            err.to_string(),
+            "Log file /var/log/core.log is already used by instance a"
+        );
+    }
+
+    #[test]
+    fn rejects_controller_of_other_instance() {
+        let others = [(
+            "a".to_string(),
+            body("/var/log/a.log", &["-ext-ctl=0.0.0.0:9090"]),
+        )];
+        let err = check_claim(&body("/var/log/b.log", &["-ext-ctl=:9090"]), &others).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "External controller 127.0.0.1:9090 is already used by instance a"
+        );
+    }
+}
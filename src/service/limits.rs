@@ -0,0 +1,119 @@
+use super::data::ResourceLimits;
+use std::{io, process::Command};
+
+/// Apply `limits` to the core between fork and exec
+#[cfg(unix)]
+pub fn apply(command: &mut Command, limits: &ResourceLimits) {
+    use std::os::unix::process::CommandExt;
+
+    if limits.is_empty() {
+        return;
+    }
+    // pre_exec 中只能调用系统调用，所有内存分配都在 fork 之前完成
+    let nofile = limits.nofile;
+    let address_space = limits.address_space;
+    let nice = limits.nice;
+    #[cfg(target_os = "linux")]
+    let cpu_set = limits.cpu_affinity.as_deref().map(cpu_set);
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nofile) = nofile {
+                set_rlimit(libc::RLIMIT_NOFILE, nofile)?;
+            }
+            if let Some(address_space) = address_space {
+                set_rlimit(libc::RLIMIT_AS, address_space)?;
+            }
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(cpu_set) = &cpu_set {
+                let size = std::mem::size_of::<libc::cpu_set_t>();
+                if libc::sched_setaffinity(0, size, cpu_set) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Limits are rejected during validation on platforms without them
+#[cfg(not(unix))]
+pub fn apply(_command: &mut Command, _limits: &ResourceLimits) {}
+
+#[cfg(unix)]
+unsafe fn set_rlimit(resource: RlimitResource, value: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    if libc::setrlimit(resource, &limit) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type RlimitResource = libc::c_int;
+
+#[cfg(target_os = "linux")]
+fn cpu_set(cpus: &[usize]) -> libc::cpu_set_t {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    set
+}
+
+/// The limits `pid` actually runs with. Linux reads them back from the
+/// process, including ones the service left alone; elsewhere the applied
+/// values are all we know.
+#[cfg(target_os = "linux")]
+pub fn effective(pid: u32, _applied: &ResourceLimits) -> ResourceLimits {
+    let pid = pid as libc::pid_t;
+    // rlim_t 在部分 32 位平台上不是 u64
+    #[allow(clippy::unnecessary_cast)]
+    let read_rlimit = |resource| {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        let ok = unsafe { libc::prlimit(pid, resource, std::ptr::null(), &mut limit) } == 0;
+        // 无限制时返回 None
+        (ok && limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur as u64)
+    };
+
+    let nice = unsafe {
+        // getpriority 可能合法地返回 -1，需要通过 errno 区分错误
+        *libc::__errno_location() = 0;
+        let nice = libc::getpriority(libc::PRIO_PROCESS, pid as libc::id_t);
+        (*libc::__errno_location() == 0).then_some(nice)
+    };
+
+    let cpu_affinity = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        (libc::sched_getaffinity(pid, size, &mut set) == 0).then(|| {
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+                .collect()
+        })
+    };
+
+    ResourceLimits {
+        nofile: read_rlimit(libc::RLIMIT_NOFILE),
+        address_space: read_rlimit(libc::RLIMIT_AS),
+        nice,
+        cpu_affinity,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn effective(_pid: u32, applied: &ResourceLimits) -> ResourceLimits {
+    applied.clone()
+}
@@ -0,0 +1,70 @@
+use super::config::ServiceConfig;
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::{chown, Gid, Group, Uid, User};
+use std::{
+    fs,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+};
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+
+/// Bind the control socket, replacing a stale one left by a previous run,
+/// and apply the configured owner, group and mode before accepting clients.
+pub fn bind_unix_socket(config: &ServiceConfig) -> Result<UnixListenerStream> {
+    let path = &config.socket_path;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create socket directory: {}", parent.display()))?;
+    }
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!(
+                "Refusing to replace non-socket file: {}",
+                path.display()
+            ));
+        }
+        fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket: {}", path.display()))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind unix socket: {}", path.display()))?;
+
+    let uid = match &config.socket_owner {
+        Some(name) => Some(lookup_user(name)?),
+        None => None,
+    };
+    let gid = match &config.socket_group {
+        Some(name) => Some(lookup_group(name)?),
+        None => None,
+    };
+    if uid.is_some() || gid.is_some() {
+        chown(path, uid, gid)
+            .with_context(|| format!("Failed to change socket owner: {}", path.display()))?;
+    }
+
+    fs::set_permissions(path, fs::Permissions::from_mode(config.socket_mode))
+        .with_context(|| format!("Failed to change socket mode: {}", path.display()))?;
+
+    Ok(UnixListenerStream::new(listener))
+}
+
+fn lookup_user(name: &str) -> Result<Uid> {
+    if let Ok(uid) = name.parse::<u32>() {
+        return Ok(Uid::from_raw(uid));
+    }
+    User::from_name(name)?
+        .map(|user| user.uid)
+        .ok_or(anyhow!("Unknown user: {}", name))
+}
+
+fn lookup_group(name: &str) -> Result<Gid> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Group::from_name(name)?
+        .map(|group| group.gid)
+        .ok_or(anyhow!("Unknown group: {}", name))
+}
@@ -0,0 +1,32 @@
+use super::config::ServiceConfig;
+use anyhow::Result;
+use log4rs::{
+    append::{console::ConsoleAppender, file::FileAppender},
+    config::{Appender, Config, Root},
+    encode::pattern::PatternEncoder,
+};
+
+const LOG_PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S)} {l} - {m}{n}";
+
+/// Log to stdout, and to `service.log` in `log_dir` when one is configured
+pub fn init(config: &ServiceConfig) -> Result<()> {
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(PatternEncoder::new(LOG_PATTERN)))
+        .build();
+
+    let mut builder =
+        Config::builder().appender(Appender::builder().build("stdout", Box::new(stdout)));
+    let mut root = Root::builder().appender("stdout");
+
+    if let Some(log_dir) = &config.log_dir {
+        let file = FileAppender::builder()
+            .encoder(Box::new(PatternEncoder::new(LOG_PATTERN)))
+            .build(log_dir.join("service.log"))?;
+        builder = builder.appender(Appender::builder().build("file", Box::new(file)));
+        root = root.appender("file");
+    }
+
+    let log_config = builder.build(root.build(config.log_level_filter()?))?;
+    log4rs::init_config(log_config)?;
+    Ok(())
+}
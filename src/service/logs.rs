@@ -0,0 +1,118 @@
+use super::{rotate::RotatingFile, shutdown};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    io::{BufRead, BufReader, Read},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt,
+};
+use warp::{sse::Event, Reply};
+
+/// Lines kept in memory for clients asking for a backlog
+pub const BACKLOG_CAPACITY: usize = 1000;
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Fan-out of the core's output to live subscribers, with a bounded backlog
+pub struct LogHub {
+    sender: broadcast::Sender<String>,
+    backlog: Mutex<VecDeque<String>>,
+}
+
+impl LogHub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        LogHub {
+            sender,
+            backlog: Mutex::new(VecDeque::with_capacity(BACKLOG_CAPACITY)),
+        }
+    }
+
+    pub fn push(&self, line: String) {
+        let mut backlog = self.backlog.lock().unwrap();
+        if backlog.len() == BACKLOG_CAPACITY {
+            backlog.pop_front();
+        }
+        backlog.push_back(line.clone());
+        // 没有订阅者时发送失败是正常的
+        let _ = self.sender.send(line);
+    }
+
+    /// The last `backlog` lines plus a receiver for everything after them
+    pub fn subscribe(&self, backlog: usize) -> (Vec<String>, broadcast::Receiver<String>) {
+        // 持锁订阅，保证历史行与实时行之间不重不漏
+        let lines = self.backlog.lock().unwrap();
+        let receiver = self.sender.subscribe();
+        let skip = lines.len().saturating_sub(backlog);
+        (lines.iter().skip(skip).cloned().collect(), receiver)
+    }
+}
+
+/// Which pipe of the core a line came from
+#[derive(Debug, Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    /// Prefix for lines of this stream; stdout stays unmarked so the log
+    /// reads the same as when the core wrote it directly
+    fn marker(self) -> &'static str {
+        match self {
+            Stream::Stdout => "",
+            Stream::Stderr => "[stderr] ",
+        }
+    }
+}
+
+/// Copy `reader` line by line into the shared log file and the hub until
+/// EOF, prefixing each line with the stream marker
+pub fn pump<R: Read + Send + 'static>(
+    reader: R,
+    stream: Stream,
+    log: Arc<Mutex<RotatingFile>>,
+    hub: Arc<LogHub>,
+) {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf);
+                    let line =
+                        format!("{}{}", stream.marker(), line.trim_end_matches(['\r', '\n']));
+                    {
+                        // 两个管道共用一个文件，整行写入避免交错
+                        let _ = log.lock().unwrap().write_line(&line);
+                    }
+                    hub.push(line);
+                }
+            }
+        }
+    });
+}
+
+/// Server-Sent Events stream of the core output, starting with up to
+/// `backlog` buffered lines. Lines dropped because the client fell behind
+/// are reported as a `lagged` event carrying the number of missed lines.
+pub fn sse_reply(hub: &LogHub, backlog: usize) -> impl Reply {
+    let (lines, receiver) = hub.subscribe(backlog);
+    let history = tokio_stream::iter(lines).map(|line| Event::default().data(line));
+    let live = BroadcastStream::new(receiver).map(|line| match line {
+        Ok(line) => Event::default().data(line),
+        Err(BroadcastStreamRecvError::Lagged(missed)) => {
+            Event::default().event("lagged").data(missed.to_string())
+        }
+    });
+    let events = history.chain(live).map(Ok::<_, Infallible>);
+    // 服务退出时结束推送，否则优雅退出会一直等待该连接
+    let events = futures_util::StreamExt::take_until(events, shutdown::requested());
+    warp::sse::reply(warp::sse::keep_alive().stream(events))
+}
@@ -1,10 +1,34 @@
+mod auth;
+mod config;
+mod controller;
 mod core;
 mod data;
+mod error;
+mod events;
+mod guard;
+mod health;
+mod instances;
+mod limits;
+#[cfg(unix)]
+mod listener;
+mod logger;
+mod logs;
+mod output;
+mod privileges;
 mod process;
+mod rotate;
+mod shutdown;
+mod state;
+mod supervisor;
+mod v2;
+mod validate;
 
-use self::data::*;
+use self::{data::*, error::ServiceError};
+use log::{error, info};
+use ssrapid_desktop_service::token;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
 use tokio::runtime::Runtime;
-use warp::Filter;
+use warp::{Filter, Rejection, Reply};
 
 #[cfg(target_os = "macos")]
 use ssrapid_desktop_service::utils;
@@ -26,7 +50,6 @@ use windows_service::{
 const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
 #[cfg(not(target_os = "macos"))]
 const SERVICE_NAME: &str = "ssrapid_desktop_service";
-const LISTEN_PORT: u16 = 33211;
 
 macro_rules! wrap_response {
     ($expr: expr) => {
@@ -45,6 +68,17 @@ macro_rules! wrap_response {
     };
 }
 
+/// Turn filter rejections into JSON responses with a matching HTTP status
+async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let err = ServiceError::from_rejection(&err);
+    let json = warp::reply::json(&JsonResponse {
+        code: err.status().as_u16() as u64,
+        msg: err.to_string(),
+        data: Option::<()>::None,
+    });
+    Ok(warp::reply::with_status(json, err.status()))
+}
+
 /// The Service
 pub async fn run_service() -> anyhow::Result<()> {
     // 开启服务 设置服务状态
@@ -54,7 +88,10 @@ pub async fn run_service() -> anyhow::Result<()> {
         move |event| -> ServiceControlHandlerResult {
             match event {
                 ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
-                ServiceControl::Stop => std::process::exit(0),
+                ServiceControl::Stop => {
+                    shutdown::request();
+                    ServiceControlHandlerResult::NoError
+                }
                 _ => ServiceControlHandlerResult::NotImplemented,
             }
         },
@@ -70,68 +107,149 @@ pub async fn run_service() -> anyhow::Result<()> {
         process_id: None,
     })?;
 
+    let config = config::get();
+    shutdown::listen_for_signals();
+
+    // 所有接口都需要携带安装时生成的 token
+    let token_path = token::token_file_path();
+    let service_token = Arc::new(token::load_or_create_token(&token_path)?);
+    info!("Loaded service token from {}", token_path.display());
+
+    // 服务重启后接管上次启动且仍在运行的内核
+    instances::recover().await;
+
     let api_get_version = warp::get()
         .and(warp::path("version"))
-        .map(move || wrap_response!(COREMANAGER.lock().unwrap().get_version()));
+        .map(move || wrap_response!(COREMANAGER.get_version()));
 
     let api_start_clash = warp::post()
         .and(warp::path("start_clash"))
         .and(warp::body::json())
-        .map(move |body: StartBody| wrap_response!(COREMANAGER.lock().unwrap().start_clash(body)));
+        .and_then(|body: StartBody| async move {
+            Ok::<_, Rejection>(wrap_response!(COREMANAGER.start_clash(body).await))
+        });
 
     let api_stop_clash = warp::post()
         .and(warp::path("stop_clash"))
-        .map(move || wrap_response!(COREMANAGER.lock().unwrap().stop_mihomo()));
+        .and_then(|| async { Ok::<_, Rejection>(wrap_response!(COREMANAGER.stop_mihomo().await)) });
 
     let api_get_clash = warp::get()
         .and(warp::path("get_clash"))
-        .map(move || wrap_response!(COREMANAGER.lock().unwrap().get_clash_status()));
+        .map(move || wrap_response!(COREMANAGER.get_clash_status()));
+
+    let api_get_status = warp::get()
+        .and(warp::path("get_status"))
+        .map(move || wrap_response!(Ok::<_, ServiceError>(COREMANAGER.get_core_status())));
 
     let api_stop_service = warp::post()
         .and(warp::path("stop_service"))
-        .map(|| wrap_response!(stop_service()));
+        .and_then(|| async { Ok::<_, Rejection>(wrap_response!(stop_service_blocking().await)) });
+
+    let api_get_logs = warp::get()
+        .and(warp::path("logs"))
+        .and(warp::query::<LogQuery>())
+        .map(|query: LogQuery| logs::sse_reply(&COREMANAGER.log_hub, query.backlog.unwrap_or(0)));
+
+    let api_get_events = warp::get()
+        .and(warp::path("events"))
+        .and(warp::ws())
+        .map(|ws: warp::ws::Ws| events::ws_reply(ws, COREMANAGER.events.subscribe()));
 
     let api_exit_sys = warp::post()
         .and(warp::path("exit_sys"))
-        .map(move || wrap_response!(COREMANAGER.lock().unwrap().stop_clash()));
-
-    warp::serve(
-        api_get_version
-            .or(api_start_clash)
-            .or(api_stop_clash)
-            .or(api_stop_service)
-            .or(api_get_clash)
-            .or(api_exit_sys),
-    )
-    .run(([127, 0, 0, 1], LISTEN_PORT))
-    .await;
+        .and_then(|| async { Ok::<_, Rejection>(wrap_response!(COREMANAGER.stop_clash().await)) });
 
-    Ok(())
-}
+    let legacy_api = api_get_version
+        .or(api_start_clash)
+        .or(api_stop_clash)
+        .or(api_stop_service)
+        .or(api_get_clash)
+        .or(api_get_status)
+        .or(api_get_logs)
+        .or(api_get_events)
+        .or(api_exit_sys);
 
-// 停止服务
-#[cfg(target_os = "windows")]
-fn stop_service() -> Result<()> {
-    let status_handle =
-        service_control_handler::register(SERVICE_NAME, |_| ServiceControlHandlerResult::NoError)?;
+    // Host/Origin 检查只用于 TCP，见 guard::local_request
+    let routes = |allowed_hosts: Option<Arc<Vec<String>>>| {
+        let checks =
+            guard::local_request(allowed_hosts).and(auth::bearer_token(service_token.clone()));
+        // 旧版桌面端使用的接口，错误时仍返回 code: 400 的 JSON
+        let legacy_routes = checks.clone().and(legacy_api).recover(handle_rejection);
+        let v2_routes =
+            warp::path("v2").and(checks.and(v2::routes()).recover(v2::handle_rejection));
+        v2_routes.or(legacy_routes)
+    };
+    let allowed_hosts = guard::allowed_hosts(config.listen_addr, config.port);
 
+    let listen_addr = SocketAddr::new(config.listen_addr, config.port);
+    let (bound_addr, tcp_server) = warp::serve(routes(Some(Arc::new(allowed_hosts))))
+        .try_bind_with_graceful_shutdown(listen_addr, shutdown::requested())?;
+    info!("Listening on {}", bound_addr);
+
+    // 同时在 Unix socket 上提供相同的接口，通过文件权限控制访问
+    #[cfg(unix)]
+    match listener::bind_unix_socket(config) {
+        Ok(incoming) => {
+            info!("Listening on unix socket: {}", config.socket_path.display());
+            let unix_server = warp::serve(routes(None))
+                .serve_incoming_with_graceful_shutdown(incoming, shutdown::requested());
+            tokio::join!(tcp_server, unix_server);
+            let _ = std::fs::remove_file(&config.socket_path);
+        }
+        Err(e) => {
+            error!("Failed to listen on unix socket: {:#}", e);
+            tcp_server.await;
+        }
+    }
+
+    #[cfg(windows)]
+    tcp_server.await;
+
+    // 服务退出前停止由本服务启动的内核，避免留下孤儿进程
+    info!("Shutting down, stopping managed cores");
+    let stopped = instances::shutdown().await;
+    if let Err(e) = &stopped {
+        error!("Failed to stop core during shutdown: {:#}", e);
+    }
+
+    #[cfg(windows)]
     status_handle.set_service_status(ServiceStatus {
         service_type: SERVICE_TYPE,
         current_state: ServiceState::Stopped,
         controls_accepted: ServiceControlAccept::empty(),
-        exit_code: ServiceExitCode::Win32(0),
+        exit_code: match stopped {
+            Ok(_) => ServiceExitCode::Win32(0),
+            Err(_) => ServiceExitCode::ServiceSpecific(1),
+        },
         checkpoint: 0,
         wait_hint: Duration::default(),
         process_id: None,
     })?;
 
+    info!("Service stopped");
+    stopped
+}
+
+/// `stop_service` waits on systemctl/launchctl/the SCM, so keep it off the
+/// async workers
+async fn stop_service_blocking() -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(stop_service).await?
+}
+
+// 停止服务
+#[cfg(target_os = "windows")]
+fn stop_service() -> anyhow::Result<()> {
+    // run_service 退出时会停止内核并上报 Stopped 状态
+    shutdown::request();
     Ok(())
 }
 #[cfg(target_os = "linux")]
 fn stop_service() -> anyhow::Result<()> {
-    // systemctl stop ssrapid_desktop_service
+    // systemctl stop --no-block ssrapid_desktop_service
+    // 不等待 systemd，否则 SIGTERM 触发的优雅退出会等待本请求结束而互相阻塞
     std::process::Command::new("systemctl")
         .arg("stop")
+        .arg("--no-block")
         .arg(SERVICE_NAME)
         .output()
         .expect("failed to execute process");
@@ -156,14 +274,31 @@ pub fn main() -> Result<()> {
     service_dispatcher::start(SERVICE_NAME, ffi_service_main)
 }
 
+/// Load the service config and set up logging before anything else runs
+fn init_config() -> anyhow::Result<()> {
+    let config = config::init()?;
+    logger::init(config)?;
+    match &config.config_file {
+        Some(path) => info!("Loaded service config from {}", path.display()),
+        None => info!("No service config file found, using defaults"),
+    }
+    Ok(())
+}
+
 #[cfg(not(windows))]
 pub fn main() {
-    #[cfg(target_os = "linux")]
-    core::init_signal_handler();
+    if let Err(e) = init_config() {
+        eprintln!("{:#}", e);
+        std::process::exit(2);
+    }
     if let Ok(rt) = Runtime::new() {
-        rt.block_on(async {
-            let _ = run_service().await;
-        });
+        let result = rt.block_on(run_service());
+        // 不等待仍在阻塞线程上运行的任务
+        rt.shutdown_background();
+        if let Err(e) = result {
+            error!("Service exited with error: {:#}", e);
+            std::process::exit(1);
+        }
     }
 }
 
@@ -172,9 +307,15 @@ define_windows_service!(ffi_service_main, my_service_main);
 
 #[cfg(windows)]
 pub fn my_service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = init_config() {
+        eprintln!("{:#}", e);
+        return;
+    }
     if let Ok(rt) = Runtime::new() {
         rt.block_on(async {
-            let _ = run_service().await;
+            if let Err(e) = run_service().await {
+                error!("Service exited with error: {:#}", e);
+            }
         });
     }
 }
@@ -0,0 +1,130 @@
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Buffer for output the core writes while no service is reading it, e.g.
+/// during a service restart; the core blocks on its log once it is full
+#[cfg(target_os = "linux")]
+const PIPE_SIZE: libc::c_int = 1 << 20;
+
+/// Named pipes carrying the core's stdout and stderr. Unlike anonymous
+/// pipes they can be reopened by a restarted service, and the core keeps a
+/// read end of each open itself, so it never gets SIGPIPE while the service
+/// is gone.
+pub struct Fifos {
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+}
+
+/// Both ends of one pipe: the service reads, the core writes
+pub struct Pipe {
+    pub read: File,
+    pub write: File,
+}
+
+impl Fifos {
+    /// The FIFOs of the core recorded in `state_file`, next to it
+    pub fn new(state_file: &Path) -> Self {
+        Fifos {
+            stdout: state_file.with_extension("stdout"),
+            stderr: state_file.with_extension("stderr"),
+        }
+    }
+
+    /// Replace any FIFOs left from an earlier core and open new ones as
+    /// `(stdout, stderr)`
+    #[cfg(unix)]
+    pub fn create(&self) -> io::Result<(Pipe, Pipe)> {
+        // 状态文件要等内核启动后才保存，它的目录此时可能还不存在
+        if let Some(dir) = self.stdout.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok((create(&self.stdout)?, create(&self.stderr)?))
+    }
+
+    /// Reopen the read ends of the FIFOs of an adopted core as
+    /// `(stdout, stderr)`; output it wrote in the meantime is still buffered
+    #[cfg(unix)]
+    pub fn reopen(&self) -> io::Result<(File, File)> {
+        Ok((open_read(&self.stdout)?, open_read(&self.stderr)?))
+    }
+
+    #[cfg(not(unix))]
+    pub fn create(&self) -> io::Result<(Pipe, Pipe)> {
+        Err(unsupported())
+    }
+
+    #[cfg(not(unix))]
+    pub fn reopen(&self) -> io::Result<(File, File)> {
+        Err(unsupported())
+    }
+
+    pub fn remove(&self) {
+        for path in [&self.stdout, &self.stderr] {
+            if let Err(e) = std::fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    log::warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "core output cannot be reattached on this platform",
+    )
+}
+
+#[cfg(unix)]
+fn create(path: &Path) -> io::Result<Pipe> {
+    use nix::sys::stat::Mode;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    nix::unistd::mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+    // 先打开读端，写端才不会阻塞
+    let read = open_read(path)?;
+    let write = std::fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // 扩大缓冲失败不影响使用，保持默认大小即可
+        unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, PIPE_SIZE) };
+    }
+    Ok(Pipe { read, write })
+}
+
+/// Open the read end without waiting for a writer, then switch it back to
+/// blocking reads for the pump
+#[cfg(unix)]
+fn open_read(path: &Path) -> io::Result<File> {
+    use std::os::unix::{fs::FileTypeExt, fs::OpenOptionsExt, io::AsRawFd};
+
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)?;
+    if !file.metadata()?.file_type().is_fifo() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a FIFO", path.display()),
+        ));
+    }
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
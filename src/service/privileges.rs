@@ -0,0 +1,135 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::process::Command;
+
+/// Capabilities an unprivileged core keeps as ambient capabilities: enough
+/// to create the TUN device, set routes and bind low ports
+#[cfg(target_os = "linux")]
+const CORE_CAPABILITIES: [u32; 3] = [
+    10, // CAP_NET_BIND_SERVICE
+    12, // CAP_NET_ADMIN
+    13, // CAP_NET_RAW
+];
+
+/// The account the core runs as instead of root
+#[derive(Debug, Clone, Serialize)]
+pub struct CoreUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Look up `user`, with `group` or else the user's primary group
+#[cfg(target_os = "linux")]
+pub fn resolve(user: &str, group: Option<&str>) -> Result<CoreUser> {
+    use nix::unistd::{Group, User};
+
+    let account = User::from_name(user)?.ok_or(anyhow!("Unknown user: {}", user))?;
+    let gid = match group {
+        Some(group) => {
+            Group::from_name(group)?
+                .ok_or(anyhow!("Unknown group: {}", group))?
+                .gid
+        }
+        None => account.gid,
+    };
+    Ok(CoreUser {
+        name: account.name,
+        uid: account.uid.as_raw(),
+        gid: gid.as_raw(),
+    })
+}
+
+/// Ambient capabilities are Linux only; dropping root elsewhere would leave
+/// the core unable to set up TUN mode
+#[cfg(not(target_os = "linux"))]
+pub fn resolve(_user: &str, _group: Option<&str>) -> Result<CoreUser> {
+    Err(anyhow!(
+        "Running the core as another user is only supported on Linux"
+    ))
+}
+
+/// Switch the core to `user` between fork and exec, keeping only
+/// `CORE_CAPABILITIES`. Must come after any pre_exec step that needs root.
+#[cfg(target_os = "linux")]
+pub fn apply(command: &mut Command, user: &CoreUser) {
+    use std::os::unix::process::CommandExt;
+
+    let (uid, gid) = (user.uid, user.gid);
+    unsafe {
+        command.pre_exec(move || drop_privileges(uid, gid));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_command: &mut Command, _user: &CoreUser) {}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[cfg(target_os = "linux")]
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[cfg(target_os = "linux")]
+fn drop_privileges(uid: libc::uid_t, gid: libc::gid_t) -> std::io::Result<()> {
+    fn check(ret: libc::c_long) -> std::io::Result<()> {
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    let mask = CORE_CAPABILITIES
+        .iter()
+        .fold(0u32, |mask, cap| mask | (1 << cap));
+    let header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapData {
+            effective: mask,
+            permitted: mask,
+            inheritable: mask,
+        },
+        CapData::default(),
+    ];
+
+    unsafe {
+        // setuid 之后保留 permitted 集合，再收缩到需要的几项
+        check(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0).into())?;
+        check(libc::setgroups(1, &gid).into())?;
+        check(libc::setgid(gid).into())?;
+        check(libc::setuid(uid).into())?;
+        check(libc::syscall(libc::SYS_capset, &header, data.as_ptr()))?;
+        // ambient 能力在 exec 之后仍然有效
+        for cap in CORE_CAPABILITIES {
+            check(
+                libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_RAISE,
+                    cap as libc::c_ulong,
+                    0,
+                    0,
+                )
+                .into(),
+            )?;
+        }
+        // 禁止通过 setuid 程序重新获得 root
+        check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0).into())?;
+    }
+    Ok(())
+}
@@ -1,55 +1,246 @@
+use super::{
+    data::ResourceLimits,
+    limits,
+    logs::{self, LogHub, Stream},
+    output::Pipe,
+    privileges::{self, CoreUser},
+    rotate::RotatingFile,
+};
+use serde::{Deserialize, Serialize};
 #[cfg(not(target_os = "windows"))]
-use std::process::Output;
+use std::time::Instant;
 use std::{
-    io::{self, Write},
+    collections::BTreeMap,
+    io::{self, Read},
+    path::{Path, PathBuf},
     process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-pub fn spawn_process(command: &str, args: &[&str], mut log: std::fs::File) -> io::Result<u32> {
+/// How a spawned process ended; both are `None` for an adopted core, whose
+/// exit status the service cannot observe
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExitInfo {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+impl From<std::process::ExitStatus> for ExitInfo {
+    fn from(status: std::process::ExitStatus) -> Self {
+        #[cfg(unix)]
+        let signal = {
+            use std::os::unix::process::ExitStatusExt;
+            status.signal()
+        };
+        #[cfg(not(unix))]
+        let signal = None;
+
+        ExitInfo {
+            code: status.code(),
+            signal,
+        }
+    }
+}
+
+/// How the core is set up between fork and exec
+#[derive(Debug, Clone, Default)]
+pub struct SpawnOptions {
+    pub limits: ResourceLimits,
+    /// Run as this user with only network capabilities instead of as root
+    pub user: Option<CoreUser>,
+    pub env: BTreeMap<String, String>,
+    pub working_dir: Option<PathBuf>,
+}
+
+/// Spawn the core with its stdout and stderr going through the service into
+/// `log` and `hub`: over the FIFO `pipes` if given, so a restarted service
+/// can pick the output up again, else over anonymous pipes. `on_exit` runs on a
+/// waiter thread with the pid once the process has ended. Returns the
+/// identity needed to signal it later.
+pub fn spawn_process(
+    command: &str,
+    args: &[&str],
+    mut log: RotatingFile,
+    hub: Arc<LogHub>,
+    pipes: Option<(Pipe, Pipe)>,
+    options: &SpawnOptions,
+    on_exit: impl FnOnce(u32, ExitInfo) + Send + 'static,
+) -> io::Result<ProcessIdentity> {
     // Log the command being executed
-    let _ = writeln!(log, "Spawning process: {} {}", command, args.join(" "));
-    log.flush()?;
+    log.write_line(&format!("Spawning process: {} {}", command, args.join(" ")))?;
 
-    #[cfg(target_os = "macos")]
-    {
-        // On macOS, use posix_spawn via Command
-        let child = Command::new(command)
-            .args(args)
-            .stdout(Stdio::from(log))
-            .stderr(Stdio::null())
-            .spawn()?;
-
-        // Get the process ID
-        let pid = child.id();
-
-        // Detach the child process
-        std::thread::spawn(move || {
-            let _ = child.wait_with_output();
-        });
+    let mut command = Command::new(command);
+    command.args(args).envs(&options.env);
+    let readers = match pipes {
+        Some((stdout, stderr)) => {
+            #[cfg(unix)]
+            {
+                use std::os::unix::io::AsRawFd;
+                keep_open(
+                    &mut command,
+                    [stdout.read.as_raw_fd(), stderr.read.as_raw_fd()],
+                );
+            }
+            command.stdout(stdout.write).stderr(stderr.write);
+            Some((stdout.read, stderr.read))
+        }
+        None => {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+            None
+        }
+    };
+    if let Some(dir) = &options.working_dir {
+        command.current_dir(dir);
+    }
+    #[cfg(unix)]
+    new_session(&mut command);
+    // 先应用资源限制，降权之后就没有权限提高这些限制了
+    limits::apply(&mut command, &options.limits);
+    if let Some(user) = &options.user {
+        privileges::apply(&mut command, user);
+    }
+    let mut child = command.spawn()?;
 
-        Ok(pid)
+    // Get the process ID
+    let pid = child.id();
+    let Some(identity) = ProcessIdentity::capture(pid) else {
+        // 没有身份就无法在之后安全地发送信号，不能留下这个进程
+        discard_child(&mut child);
+        return Err(io::Error::other(format!(
+            "Failed to read the identity of spawned process {}",
+            pid
+        )));
+    };
+
+    match readers {
+        Some((stdout, stderr)) => attach_output(stdout, stderr, log, hub),
+        None => {
+            if let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) {
+                attach_output(stdout, stderr, log, hub);
+            }
+        }
     }
 
-    #[cfg(not(target_os = "macos"))]
-    {
-        let child = Command::new(command)
-            .args(args)
-            .stdout(log)
-            .stderr(Stdio::null())
-            .spawn()?;
-        Ok(child.id())
+    // Detach the child process
+    std::thread::spawn(move || {
+        // 只有这个线程等待该子进程，退出码不会被其他地方回收
+        let exit = child.wait().map(ExitInfo::from).unwrap_or_default();
+        on_exit(pid, exit);
+    });
+
+    Ok(identity)
+}
+
+/// Copy the core's output into `log` and `hub` until it closes; also used
+/// for the reopened FIFOs of an adopted core
+pub fn attach_output(
+    stdout: impl Read + Send + 'static,
+    stderr: impl Read + Send + 'static,
+    log: RotatingFile,
+    hub: Arc<LogHub>,
+) {
+    let log = Arc::new(Mutex::new(log));
+    logs::pump(stdout, Stream::Stdout, log.clone(), hub.clone());
+    logs::pump(stderr, Stream::Stderr, log, hub);
+}
+
+/// Let the core inherit `fds`, the service's read ends of its FIFOs, so the
+/// pipes keep a reader while the service is restarting
+#[cfg(unix)]
+fn keep_open(command: &mut Command, fds: [std::os::unix::io::RawFd; 2]) {
+    use std::os::unix::process::CommandExt;
+
+    unsafe {
+        command.pre_exec(move || {
+            for fd in fds {
+                if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Kill and reap a child that was just spawned, along with anything it
+/// forked in the meantime
+fn discard_child(child: &mut std::process::Child) {
+    #[cfg(unix)]
+    let _ = nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(-(child.id() as i32)),
+        nix::sys::signal::Signal::SIGKILL,
+    );
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Make the core the leader of a new session and process group, so
+/// `kill_process` can reach the helpers it forks as well
+#[cfg(unix)]
+fn new_session(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    unsafe {
+        command.pre_exec(|| nix::unistd::setsid().map(drop).map_err(io::Error::from));
     }
 }
 
-pub fn spawn_process_debug(command: &str, args: &[&str]) -> io::Result<(u32, String, i32)> {
-    let child = Command::new(command)
-        .args(args)
+/// Watch a core adopted from a previous run of the service. It is not our
+/// child, so there is no exit status: poll until the pid no longer belongs
+/// to it, then call `on_exit` like the waiter in `spawn_process` does.
+pub fn watch_adopted(
+    process: ProcessIdentity,
+    on_exit: impl FnOnce(u32, ExitInfo) + Send + 'static,
+) {
+    std::thread::spawn(move || {
+        while process.is_current() {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        on_exit(process.pid, ExitInfo::default());
+    });
+}
+
+/// Run blocking process work on tokio's blocking pool so it cannot stall
+/// the async workers serving other requests
+pub async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Run a short-lived command (the config test) to completion and collect its
+/// output. tokio waits for this exact child, so its exit status is always
+/// the real one. Everything in `options` is applied as for the core: the
+/// test parses the same config and may write the same files.
+pub async fn spawn_process_debug(
+    command: &str,
+    args: &[&str],
+    options: &SpawnOptions,
+) -> io::Result<(u32, String, i32)> {
+    let mut command = Command::new(command);
+    command.args(args).envs(&options.env);
+    if let Some(dir) = &options.working_dir {
+        command.current_dir(dir);
+    }
+    // 与 spawn_process 相同，先应用资源限制再降权
+    limits::apply(&mut command, &options.limits);
+    if let Some(user) = &options.user {
+        privileges::apply(&mut command, user);
+    }
+    let mut command = tokio::process::Command::from(command);
+    command
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()?;
+        .kill_on_drop(true);
+    let child = command.spawn()?;
 
-    let pid = child.id();
-    let output = child.wait_with_output()?;
+    let pid = child.id().unwrap_or_default();
+    let output = child.wait_with_output().await?;
 
     // Combine stdout and stderr
     let mut combined_output = String::new();
@@ -69,24 +260,314 @@ pub fn spawn_process_debug(command: &str, args: &[&str]) -> io::Result<(u32, Str
     Ok((pid, combined_output, exit_code))
 }
 
+/// How `kill_process` ended a process
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Termination {
+    pub pid: u32,
+    /// Signal that made the process exit; `None` on Windows or if it had
+    /// already exited
+    pub signal: Option<&'static str>,
+    /// Whether the process had to be killed after the grace period
+    pub forced: bool,
+}
+
+/// What a pid referred to when the service spawned it. The pid alone is not
+/// enough to signal later: once the core exits and is reaped, the pid can be
+/// handed to an unrelated process.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessIdentity {
+    pub pid: u32,
+    exe: Option<PathBuf>,
+    start_time: Option<u64>,
+}
+
+impl ProcessIdentity {
+    /// Record the executable and start time of a freshly spawned `pid`;
+    /// `None` if they cannot be read
+    pub fn capture(pid: u32) -> Option<Self> {
+        let (exe, start_time) = lookup(pid)?;
+        Some(ProcessIdentity {
+            pid,
+            exe,
+            start_time: Some(start_time),
+        })
+    }
+
+    pub fn started_at(&self) -> Option<SystemTime> {
+        self.start_time
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Whether `pid` still belongs to the process recorded at spawn. The
+    /// core binary may have been replaced on disk since, e.g. by an update.
+    pub fn is_current(&self) -> bool {
+        let Some(start_time) = self.start_time else {
+            return false;
+        };
+        lookup(self.pid).is_some_and(|(exe, current_start)| {
+            current_start == start_time && (self.exe.is_none() || exe == self.exe)
+        })
+    }
+}
+
+fn lookup(pid: u32) -> Option<(Option<PathBuf>, u64)> {
+    use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        true,
+        ProcessRefreshKind::nothing().with_exe(UpdateKind::Always),
+    );
+    let process = system.process(pid)?;
+    Some((process.exe().map(strip_deleted), process.start_time()))
+}
+
+/// Once the binary of a running process is replaced or removed, the kernel
+/// reports its path with ` (deleted)` appended
+fn strip_deleted(exe: &Path) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let bytes = exe.as_os_str().as_bytes();
+        if let Some(path) = bytes.strip_suffix(b" (deleted)") {
+            return PathBuf::from(std::ffi::OsStr::from_bytes(path));
+        }
+    }
+    exe.to_path_buf()
+}
+
+/// Result for a process that exited (and maybe had its pid reused) before
+/// it could be signalled
+fn already_exited(pid: u32) -> Termination {
+    log::warn!(
+        "Process {} has already exited or its pid was reused, not signalling it",
+        pid
+    );
+    Termination {
+        pid,
+        signal: None,
+        forced: false,
+    }
+}
+
 #[cfg(target_os = "windows")]
-pub fn kill_process(pid: u32) -> io::Result<()> {
-    let taskkill_args = &["/F", "/PID", &pid.to_string()];
+pub fn kill_process(process: &ProcessIdentity, _grace: Duration) -> io::Result<Termination> {
+    if !process.is_current() {
+        return Ok(already_exited(process.pid));
+    }
+    // /T 连同内核启动的子进程一起结束
+    let taskkill_args = &["/F", "/T", "/PID", &process.pid.to_string()];
     Command::new("taskkill").args(taskkill_args).output()?;
-    Ok(())
+    Ok(Termination {
+        pid: process.pid,
+        signal: None,
+        forced: true,
+    })
+}
+
+/// Send SIGTERM so the core can clean up (cache, TUN device, routes), then
+/// SIGKILL if it is still around after `grace`. A core that leads its own
+/// process group is signalled together with everything it forked. Nothing
+/// is signalled once the pid no longer belongs to `process`.
+#[cfg(not(target_os = "windows"))]
+pub fn kill_process(process: &ProcessIdentity, grace: Duration) -> io::Result<Termination> {
+    use nix::{sys::signal::Signal, unistd::Pid};
+
+    let pid = Pid::from_raw(process.pid as i32);
+    if !process.is_current() {
+        return Ok(already_exited(process.pid));
+    }
+    // 接管的旧版本内核可能不是进程组组长，此时只能向它本身发送信号
+    // 组长退出后，只要组内还有进程，内核就不会复用这个 pid，可以继续向该组发送信号
+    let target = match nix::unistd::getpgid(Some(pid)) {
+        Ok(pgid) if pgid == pid => Pid::from_raw(-pid.as_raw()),
+        _ => pid,
+    };
+    let alive = || process.is_current() || (target != pid && group_exists(target));
+    if !send_signal(target, Signal::SIGTERM)? {
+        return Ok(already_exited(process.pid));
+    }
+    let deadline = Instant::now() + grace;
+    while Instant::now() < deadline {
+        if !alive() {
+            return Ok(Termination {
+                pid: process.pid,
+                signal: Some("SIGTERM"),
+                forced: false,
+            });
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    log::warn!(
+        "Process {} still running after {:?}, sending SIGKILL",
+        pid,
+        grace
+    );
+    if !alive() {
+        return Ok(Termination {
+            pid: process.pid,
+            signal: Some("SIGTERM"),
+            forced: false,
+        });
+    }
+    send_signal(target, Signal::SIGKILL)?;
+    // 等待进程被回收，保证返回后 pid 已经不存在
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while alive() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    Ok(Termination {
+        pid: process.pid,
+        signal: Some("SIGKILL"),
+        forced: true,
+    })
+}
+
+/// Whether any process is left in the group `-pgid`
+#[cfg(not(target_os = "windows"))]
+fn group_exists(pgid: nix::unistd::Pid) -> bool {
+    !matches!(
+        nix::sys::signal::kill(pgid, None),
+        Err(nix::errno::Errno::ESRCH)
+    )
 }
 
+/// False if the process was already gone; a negative `pid` signals the
+/// whole process group
 #[cfg(not(target_os = "windows"))]
-pub fn kill_process(pid: u32) -> io::Result<()> {
-    let kill_args = &["-9", &pid.to_string()];
-    let output: Output = Command::new("kill").args(kill_args).output()?;
-
-    if output.status.success() {
-        Ok(())
-    } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Kill command failed: {:?}", output),
-        ))
+fn send_signal(pid: nix::unistd::Pid, signal: nix::sys::signal::Signal) -> io::Result<bool> {
+    match nix::sys::signal::kill(pid, signal) {
+        Ok(_) => Ok(true),
+        // 进程已经退出，同样视为成功
+        Err(nix::errno::Errno::ESRCH) => Ok(false),
+        Err(e) => Err(io::Error::from(e)),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::service::rotate::RotatePolicy;
+    use std::{fs, os::unix::fs::PermissionsExt, sync::mpsc};
+
+    /// A core that forks a helper which forks a grandchild, writes the
+    /// grandchild's pid to the file given as its argument and waits
+    const FAKE_CORE: &str = "#!/bin/sh
+sh -c \"$2 sleep 600 & echo \\$! > $1; wait\" &
+wait
+";
+
+    /// Spawn `core` with its output logged in `dir`
+    fn spawn(
+        dir: &Path,
+        core: &Path,
+        args: &[&str],
+    ) -> (ProcessIdentity, mpsc::Receiver<ExitInfo>) {
+        let log = RotatingFile::open(
+            dir.join("core.log"),
+            RotatePolicy {
+                max_bytes: 1 << 20,
+                max_age: None,
+                keep: 1,
+            },
+        )
+        .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let identity = spawn_process(
+            core.to_str().unwrap(),
+            args,
+            log,
+            Arc::new(LogHub::new()),
+            None,
+            &SpawnOptions::default(),
+            move |_, exit| tx.send(exit).unwrap(),
+        )
+        .unwrap();
+        (identity, rx)
+    }
+
+    /// Spawn the fake core and return it with the grandchild's pid
+    fn spawn_fake_core(dir: &Path, trap: &str) -> (ProcessIdentity, mpsc::Receiver<ExitInfo>, i32) {
+        let core = dir.join("core");
+        fs::write(&core, FAKE_CORE).unwrap();
+        fs::set_permissions(&core, fs::Permissions::from_mode(0o755)).unwrap();
+        let pid_file = dir.join("grandchild.pid");
+        let (identity, rx) = spawn(dir, &core, &[pid_file.to_str().unwrap(), trap]);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let grandchild = loop {
+            if let Some(pid) = fs::read_to_string(&pid_file)
+                .ok()
+                .and_then(|pid| pid.trim().parse().ok())
+            {
+                break pid;
+            }
+            assert!(Instant::now() < deadline, "fake core did not fork");
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        (identity, rx, grandchild)
+    }
+
+    /// Running and not a zombie waiting for some parent to reap it
+    fn is_running(pid: i32) -> bool {
+        fs::read_to_string(format!("/proc/{}/stat", pid))
+            .ok()
+            .and_then(|stat| {
+                let state = stat.rsplit_once(')')?.1.trim_start().chars().next()?;
+                Some(state != 'Z')
+            })
+            .unwrap_or(false)
+    }
+
+    #[test]
+    fn stop_kills_grandchildren() {
+        let dir = tempfile::tempdir().unwrap();
+        let (identity, exited, grandchild) = spawn_fake_core(dir.path(), "");
+        assert!(is_running(grandchild));
+
+        let termination = kill_process(&identity, Duration::from_secs(5)).unwrap();
+        assert_eq!(termination.signal, Some("SIGTERM"));
+        assert!(!termination.forced);
+        assert!(exited.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(!is_running(grandchild));
+    }
+
+    #[test]
+    fn stop_kills_grandchildren_ignoring_sigterm() {
+        let dir = tempfile::tempdir().unwrap();
+        let (identity, exited, grandchild) = spawn_fake_core(dir.path(), "trap '' TERM;");
+
+        let termination = kill_process(&identity, Duration::from_millis(300)).unwrap();
+        assert_eq!(termination.signal, Some("SIGKILL"));
+        assert!(termination.forced);
+        assert!(exited.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(!is_running(grandchild));
+    }
+
+    #[test]
+    fn stop_finds_core_whose_binary_was_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = dir.path().join("core");
+        fs::copy("/bin/sh", &core).unwrap();
+        // 后面的 `:` 让 shell 不直接 exec 成 sleep
+        let (identity, exited) = spawn(dir.path(), &core, &["-c", "sleep 600; :"]);
+
+        // 像更新内核那样用新文件替换旧文件
+        let update = dir.path().join("core.new");
+        fs::copy("/bin/sh", &update).unwrap();
+        fs::rename(&update, &core).unwrap();
+        let exe = fs::read_link(format!("/proc/{}/exe", identity.pid)).unwrap();
+        assert!(exe.to_string_lossy().ends_with(" (deleted)"));
+        assert!(identity.is_current());
+
+        let termination = kill_process(&identity, Duration::from_secs(5)).unwrap();
+        assert_eq!(termination.signal, Some("SIGTERM"));
+        assert!(exited.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(!is_running(identity.pid as i32));
     }
 }
@@ -0,0 +1,264 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// When the core log is rotated and how many old files are kept
+#[derive(Debug, Clone, Copy)]
+pub struct RotatePolicy {
+    pub max_bytes: u64,
+    /// `None` rotates on size only
+    pub max_age: Option<Duration>,
+    /// Rotated files kept as `<log>.1` (newest) to `<log>.<keep>`
+    pub keep: usize,
+}
+
+/// Core log written by the service: rotated when it grows past the size
+/// limit or gets too old, and once on open so the previous session is kept
+/// as `<log>.1`.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+    policy: RotatePolicy,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, policy: RotatePolicy) -> io::Result<Self> {
+        let path = path.into();
+        if fs::symlink_metadata(&path).is_ok_and(|meta| meta.len() > 0) {
+            rotate_files(&path, policy.keep)?;
+        }
+        let file = open_log(&path)?;
+        Ok(RotatingFile {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            opened: SystemTime::now(),
+            policy,
+        })
+    }
+
+    /// Keep appending to the log of a core adopted after a service restart;
+    /// unlike `open`, the current file is not rotated away
+    pub fn append(path: impl Into<PathBuf>, policy: RotatePolicy) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_log(&path)?;
+        Ok(RotatingFile {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            opened: SystemTime::now(),
+            policy,
+        })
+    }
+
+    /// Append one line, rotating first if it would go over the limits
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && (self.size + len > self.policy.max_bytes || self.expired()) {
+            self.rotate()?;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn expired(&self) -> bool {
+        self.policy.max_age.is_some_and(|max_age| {
+            self.opened
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= max_age)
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        rotate_files(&self.path, self.policy.keep)?;
+        self.file = open_log(&self.path)?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Shift `<log>.N` to `<log>.N+1`, dropping the oldest, and move the current
+/// log to `<log>.1`; with `keep == 0` the current log is just removed
+fn rotate_files(path: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return remove_if_exists(path);
+    }
+    remove_if_exists(&rotated_path(path, keep))?;
+    for index in (1..keep).rev() {
+        let from = rotated_path(path, index);
+        if fs::symlink_metadata(&from).is_ok() {
+            fs::rename(&from, rotated_path(path, index + 1))?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Open for appending without following a symlink planted in place of the
+/// log after validation
+fn open_log(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_bytes: u64, max_age: Option<Duration>, keep: usize) -> RotatePolicy {
+        RotatePolicy {
+            max_bytes,
+            max_age,
+            keep,
+        }
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn rotates_previous_session_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("core.log");
+        fs::write(&log, "old\n").unwrap();
+
+        let mut file = RotatingFile::open(&log, policy(1 << 20, None, 3)).unwrap();
+        file.write_line("new").unwrap();
+        assert_eq!(read(&log), "new\n");
+        assert_eq!(read(&rotated_path(&log, 1)), "old\n");
+    }
+
+    #[test]
+    fn keeps_empty_log_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("core.log");
+        fs::write(&log, "").unwrap();
+
+        RotatingFile::open(&log, policy(1 << 20, None, 3)).unwrap();
+        assert!(log.exists());
+        assert!(!rotated_path(&log, 1).exists());
+    }
+
+    #[test]
+    fn shifts_rotated_files_and_drops_the_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("core.log");
+        fs::write(&log, "current\n").unwrap();
+        fs::write(rotated_path(&log, 1), "one\n").unwrap();
+        fs::write(rotated_path(&log, 2), "two\n").unwrap();
+
+        RotatingFile::open(&log, policy(1 << 20, None, 2)).unwrap();
+        assert_eq!(read(&log), "");
+        assert_eq!(read(&rotated_path(&log, 1)), "current\n");
+        assert_eq!(read(&rotated_path(&log, 2)), "one\n");
+        assert!(!rotated_path(&log, 3).exists());
+    }
+
+    #[test]
+    fn keep_zero_discards_old_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("core.log");
+        fs::write(&log, "old\n").unwrap();
+
+        let mut file = RotatingFile::open(&log, policy(8, None, 0)).unwrap();
+        assert_eq!(read(&log), "");
+        file.write_line("first").unwrap();
+        file.write_line("second").unwrap();
+        assert_eq!(read(&log), "second\n");
+        assert!(!rotated_path(&log, 1).exists());
+    }
+
+    #[test]
+    fn rotates_when_a_line_would_exceed_the_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("core.log");
+
+        let mut file = RotatingFile::open(&log, policy(10, None, 2)).unwrap();
+        file.write_line("12345").unwrap();
+        file.write_line("678").unwrap();
+        assert_eq!(read(&log), "12345\n678\n");
+        file.write_line("9").unwrap();
+        assert_eq!(read(&log), "9\n");
+        assert_eq!(read(&rotated_path(&log, 1)), "12345\n678\n");
+    }
+
+    #[test]
+    fn writes_long_line_to_empty_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("core.log");
+
+        let mut file = RotatingFile::open(&log, policy(4, None, 2)).unwrap();
+        file.write_line("longer than the limit").unwrap();
+        assert_eq!(read(&log), "longer than the limit\n");
+        assert!(!rotated_path(&log, 1).exists());
+    }
+
+    #[test]
+    fn rotates_when_too_old() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("core.log");
+        let max_age = Duration::from_millis(500);
+
+        let mut file = RotatingFile::open(&log, policy(1 << 20, Some(max_age), 2)).unwrap();
+        file.write_line("before").unwrap();
+        file.write_line("still fresh").unwrap();
+        assert!(!rotated_path(&log, 1).exists());
+        std::thread::sleep(max_age + Duration::from_millis(100));
+        file.write_line("after").unwrap();
+        assert_eq!(read(&log), "after\n");
+        assert_eq!(read(&rotated_path(&log, 1)), "before\nstill fresh\n");
+    }
+
+    #[test]
+    fn append_continues_the_current_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("core.log");
+        fs::write(&log, "12345\n").unwrap();
+
+        let mut file = RotatingFile::append(&log, policy(10, None, 2)).unwrap();
+        file.write_line("678").unwrap();
+        assert_eq!(read(&log), "12345\n678\n");
+        assert!(!rotated_path(&log, 1).exists());
+        // 已有内容计入大小
+        file.write_line("9").unwrap();
+        assert_eq!(read(&log), "9\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinked_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let log = dir.path().join("core.log");
+        std::os::unix::fs::symlink(&target, &log).unwrap();
+
+        assert!(RotatingFile::append(&log, policy(1 << 20, None, 2)).is_err());
+        assert!(!target.exists());
+    }
+}
@@ -0,0 +1,47 @@
+use log::info;
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// Ask the service to stop accepting requests, stop the core and exit
+pub fn request() {
+    SHUTDOWN.send_replace(true);
+}
+
+/// Resolves once a shutdown has been requested
+pub async fn requested() {
+    let mut receiver = SHUTDOWN.subscribe();
+    let _ = receiver.wait_for(|requested| *requested).await;
+}
+
+/// Turn SIGTERM/SIGINT (or Ctrl-C on Windows) into a shutdown request
+pub fn listen_for_signals() {
+    tokio::spawn(async {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let (Ok(mut sigterm), Ok(mut sigint)) = (
+                signal(SignalKind::terminate()),
+                signal(SignalKind::interrupt()),
+            ) else {
+                log::error!("Failed to install signal handlers");
+                return;
+            };
+            tokio::select! {
+                _ = sigterm.recv() => info!("Received SIGTERM"),
+                _ = sigint.recv() => info!("Received SIGINT"),
+            }
+        }
+
+        #[cfg(windows)]
+        {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            info!("Received Ctrl-C");
+        }
+
+        request();
+    });
+}
@@ -0,0 +1,56 @@
+use super::{data::StartBody, process::ProcessIdentity};
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{fs, io::Write, path::Path};
+
+/// The running core as recorded on disk, so a restarted service can find it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CoreState {
+    pub config: StartBody,
+    pub process: ProcessIdentity,
+}
+
+/// Write the state atomically (temp file + rename), readable by root only
+pub fn save(path: &Path, state: &CoreState) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create state directory: {}", parent.display()))?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600).custom_flags(libc::O_NOFOLLOW);
+    }
+    let mut file = options
+        .open(&tmp)
+        .with_context(|| format!("Failed to write state file: {}", tmp.display()))?;
+    file.write_all(&serde_json::to_vec(state)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+        .with_context(|| format!("Failed to write state file: {}", path.display()))?;
+    Ok(())
+}
+
+/// The recorded state; `None` if there is none or it cannot be read
+pub fn load(path: &Path) -> Option<CoreState> {
+    let content = fs::read(path).ok()?;
+    match serde_json::from_slice(&content) {
+        Ok(state) => Some(state),
+        Err(e) => {
+            warn!("Ignoring unreadable state file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+pub fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove state file {}: {}", path.display(), e);
+        }
+    }
+}
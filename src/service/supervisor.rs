@@ -0,0 +1,98 @@
+use super::{config, events::CoreEvent, instances, process::ExitInfo, shutdown};
+use log::{error, info, warn};
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+/// A core that stays up this long is considered healthy again and its crash
+/// streak (and with it the backoff) starts over
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Watch the core spawned as `pid` by instance `name`; `exited` fires from
+/// the process waiter once it ends. `generation` is the spawn it belongs to.
+pub fn watch(name: &str, pid: u32, generation: u64, exited: oneshot::Receiver<ExitInfo>) {
+    tokio::spawn(supervise(name.to_string(), pid, generation, exited));
+}
+
+async fn supervise(name: String, pid: u32, generation: u64, exited: oneshot::Receiver<ExitInfo>) {
+    let started = Instant::now();
+    let Ok(exit) = exited.await else {
+        return;
+    };
+    // 实例已被删除
+    let Some(core) = instances::get(&name) else {
+        return;
+    };
+
+    {
+        let _op = core.op_lock.lock().await;
+        // 主动停止或已被替换的内核不需要重启
+        if !core.mark_exited(generation) {
+            return;
+        }
+    }
+    warn!(
+        "Core process {} of instance {} exited unexpectedly (code: {:?}, signal: {:?})",
+        pid, name, exit.code, exit.signal
+    );
+
+    let (restart_count, crash_streak) = {
+        let status = core.mihomo_status.inner.lock().unwrap();
+        (status.restart_count.clone(), status.crash_streak.clone())
+    };
+    if started.elapsed() >= STABLE_RUN {
+        crash_streak.store(0, Ordering::Relaxed);
+    }
+
+    let config = config::get();
+    loop {
+        let attempt = crash_streak.fetch_add(1, Ordering::Relaxed) + 1;
+        if attempt > config.max_restarts {
+            error!(
+                "Core crashed {} times in a row, giving up on restarting it",
+                attempt
+            );
+            core.events.emit(CoreEvent::RestartsExhausted {
+                restarts: attempt - 1,
+            });
+            return;
+        }
+
+        let delay = config.restart_delay(attempt);
+        info!(
+            "Restarting core in {:?} (attempt {}/{})",
+            delay, attempt, config.max_restarts
+        );
+        core.events.emit(CoreEvent::RestartScheduled {
+            attempt,
+            delay_ms: delay.as_millis() as u64,
+        });
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown::requested() => return,
+        }
+
+        let _op = core.op_lock.lock().await;
+        // 等待期间内核被手动启动或停止，交给新的状态处理
+        if core.generation() != generation {
+            return;
+        }
+        restart_count.fetch_add(1, Ordering::Relaxed);
+        match core.start_mihomo_locked().await {
+            Ok(_) => {
+                let pid = core
+                    .mihomo_status
+                    .inner
+                    .lock()
+                    .unwrap()
+                    .running_pid
+                    .load(Ordering::Relaxed);
+                core.events.emit(CoreEvent::Restarted { pid: pid as u32 });
+                return;
+            }
+            Err(e) => error!("Failed to restart core: {}", e),
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use super::{
+    core::COREMANAGER,
+    data::{LogQuery, StartBody},
+    error::ServiceError,
+    events, instances, logs, stop_service_blocking,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::convert::Infallible;
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+/// Start requests are a few paths and short lists; anything larger is refused
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+{
+    warp::body::content_length_limit(MAX_BODY_BYTES).and(warp::body::json())
+}
+
+fn json_reply<T: Serialize>(result: Result<T, ServiceError>) -> Response {
+    match result {
+        Ok(data) => warp::reply::json(&data).into_response(),
+        Err(err) => error_reply(&err),
+    }
+}
+
+fn error_reply(err: &ServiceError) -> Response {
+    warp::reply::with_status(warp::reply::json(&err.to_response()), err.status()).into_response()
+}
+
+/// Render rejections under `/v2` with the typed error body
+pub async fn handle_rejection(err: Rejection) -> Result<Response, Infallible> {
+    Ok(error_reply(&ServiceError::from_rejection(&err)))
+}
+
+/// Resource-style routes, mounted under `/v2`
+///
+/// - `GET    /version`        service version and config
+/// - `GET    /core`           core status
+/// - `PUT    /core`           store a new core config and (re)start the core
+/// - `DELETE /core`           stop the core
+/// - `GET    /core/logs`      stream core output as SSE, `?backlog=N` replays N lines
+/// - `POST   /core/start`     start the core with the stored config
+/// - `POST   /core/restart`   restart the core with the stored config
+/// - `GET    /events`         WebSocket pushing core lifecycle events
+/// - `POST   /service/stop`   stop the service
+///
+/// `/core` and `/events` act on the `default` instance, as do the legacy
+/// routes; `/instances` has the same operations for any named instance.
+pub fn routes() -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    default_routes().or(instance_routes()).unify()
+}
+
+fn default_routes() -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let get_version = warp::path!("version")
+        .and(warp::get())
+        .map(|| json_reply(COREMANAGER.get_version().map_err(ServiceError::from)));
+
+    let get_core = warp::path!("core")
+        .and(warp::get())
+        .map(|| json_reply(Ok(COREMANAGER.get_core_status())));
+
+    let put_core = warp::path!("core")
+        .and(warp::put())
+        .and(json_body())
+        .and_then(|body: StartBody| async move {
+            let result = COREMANAGER.start_clash(body).await;
+            Ok::<_, Rejection>(json_reply(result.map(|_| COREMANAGER.get_core_status())))
+        });
+
+    let delete_core = warp::path!("core").and(warp::delete()).and_then(|| async {
+        let result = COREMANAGER.stop_core().await;
+        Ok::<_, Rejection>(json_reply(result.map(|_| COREMANAGER.get_core_status())))
+    });
+
+    let start_core = warp::path!("core" / "start")
+        .and(warp::post())
+        .and_then(|| async {
+            let result = COREMANAGER.start_core().await;
+            Ok::<_, Rejection>(json_reply(result.map(|_| COREMANAGER.get_core_status())))
+        });
+
+    let restart_core = warp::path!("core" / "restart")
+        .and(warp::post())
+        .and_then(|| async {
+            let result = COREMANAGER.restart_core().await;
+            Ok::<_, Rejection>(json_reply(result.map(|_| COREMANAGER.get_core_status())))
+        });
+
+    let core_logs = warp::path!("core" / "logs")
+        .and(warp::get())
+        .and(warp::query::<LogQuery>())
+        .map(|query: LogQuery| {
+            logs::sse_reply(&COREMANAGER.log_hub, query.backlog.unwrap_or(0)).into_response()
+        });
+
+    let events = warp::path!("events")
+        .and(warp::get())
+        .and(warp::ws())
+        .map(|ws: warp::ws::Ws| {
+            events::ws_reply(ws, COREMANAGER.events.subscribe()).into_response()
+        });
+
+    let stop = warp::path!("service" / "stop")
+        .and(warp::post())
+        .and_then(|| async {
+            let reply = match stop_service_blocking().await {
+                Ok(_) => StatusCode::ACCEPTED.into_response(),
+                Err(e) => error_reply(&ServiceError::Internal(e.to_string())),
+            };
+            Ok::<_, Rejection>(reply)
+        });
+
+    get_version
+        .or(get_core)
+        .unify()
+        .or(put_core)
+        .unify()
+        .or(delete_core)
+        .unify()
+        .or(start_core)
+        .unify()
+        .or(restart_core)
+        .unify()
+        .or(core_logs)
+        .unify()
+        .or(events)
+        .unify()
+        .or(stop)
+        .unify()
+}
+
+/// Routes for named instances
+///
+/// - `GET    /instances`                all instances and their status
+/// - `GET    /instances/{name}`         instance status
+/// - `PUT    /instances/{name}`         create the instance if needed, then as `PUT /core`
+/// - `DELETE /instances/{name}`         stop the core and remove the instance (`default` is kept)
+/// - `POST   /instances/{name}/start`   start with the stored config
+/// - `POST   /instances/{name}/restart` restart with the stored config
+/// - `POST   /instances/{name}/stop`    stop the core, keeping the instance
+/// - `GET    /instances/{name}/logs`    stream core output as SSE
+/// - `GET    /instances/{name}/events`  WebSocket pushing lifecycle events
+///
+/// A `PUT` that fails does not leave a newly created instance behind.
+fn instance_routes() -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let list = warp::path!("instances")
+        .and(warp::get())
+        .map(|| json_reply(Ok(instances::statuses())));
+
+    let get_instance = warp::path!("instances" / String)
+        .and(warp::get())
+        .map(|name: String| {
+            json_reply(instances::existing(&name).map(|core| core.get_core_status()))
+        });
+
+    let put_instance = warp::path!("instances" / String)
+        .and(warp::put())
+        .and(json_body())
+        .and_then(|name: String, body: StartBody| async move {
+            let result = instances::start(&name, body)
+                .await
+                .map(|core| core.get_core_status());
+            Ok::<_, Rejection>(json_reply(result))
+        });
+
+    let delete_instance = warp::path!("instances" / String)
+        .and(warp::delete())
+        .and_then(|name: String| async move {
+            let reply = match instances::remove(&name).await {
+                Ok(_) => StatusCode::NO_CONTENT.into_response(),
+                Err(e) => error_reply(&e),
+            };
+            Ok::<_, Rejection>(reply)
+        });
+
+    let start_instance = warp::path!("instances" / String / "start")
+        .and(warp::post())
+        .and_then(|name: String| async move {
+            let result = match instances::existing(&name) {
+                Ok(core) => core.start_core().await.map(|_| core.get_core_status()),
+                Err(e) => Err(e),
+            };
+            Ok::<_, Rejection>(json_reply(result))
+        });
+
+    let restart_instance = warp::path!("instances" / String / "restart")
+        .and(warp::post())
+        .and_then(|name: String| async move {
+            let result = match instances::existing(&name) {
+                Ok(core) => core.restart_core().await.map(|_| core.get_core_status()),
+                Err(e) => Err(e),
+            };
+            Ok::<_, Rejection>(json_reply(result))
+        });
+
+    let stop_instance = warp::path!("instances" / String / "stop")
+        .and(warp::post())
+        .and_then(|name: String| async move {
+            let result = match instances::existing(&name) {
+                Ok(core) => core.stop_core().await.map(|_| core.get_core_status()),
+                Err(e) => Err(e),
+            };
+            Ok::<_, Rejection>(json_reply(result))
+        });
+
+    let instance_logs = warp::path!("instances" / String / "logs")
+        .and(warp::get())
+        .and(warp::query::<LogQuery>())
+        .map(
+            |name: String, query: LogQuery| match instances::existing(&name) {
+                Ok(core) => {
+                    logs::sse_reply(&core.log_hub, query.backlog.unwrap_or(0)).into_response()
+                }
+                Err(e) => error_reply(&e),
+            },
+        );
+
+    let instance_events = warp::path!("instances" / String / "events")
+        .and(warp::get())
+        .and(warp::ws())
+        .map(
+            |name: String, ws: warp::ws::Ws| match instances::existing(&name) {
+                Ok(core) => events::ws_reply(ws, core.events.subscribe()).into_response(),
+                Err(e) => error_reply(&e),
+            },
+        );
+
+    list.or(get_instance)
+        .unify()
+        .or(put_instance)
+        .unify()
+        .or(delete_instance)
+        .unify()
+        .or(start_instance)
+        .unify()
+        .or(restart_instance)
+        .unify()
+        .or(stop_instance)
+        .unify()
+        .or(instance_logs)
+        .unify()
+        .or(instance_events)
+        .unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn api() -> impl Filter<Extract = (Response,), Error = Infallible> + Clone {
+        routes().recover(handle_rejection).unify()
+    }
+
+    /// Status and the `error` object of the response
+    async fn send(request: warp::test::RequestBuilder) -> (StatusCode, Value) {
+        let response = request.reply(&api()).await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), body["error"].clone())
+    }
+
+    #[tokio::test]
+    async fn unknown_path_is_not_found() {
+        let (status, error) = send(warp::test::request().path("/nope")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            error,
+            json!({ "code": "not_found", "message": "not found" })
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_method_is_not_allowed() {
+        let (status, error) = send(warp::test::request().method("PATCH").path("/core")).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            error,
+            json!({ "code": "method_not_allowed", "message": "method not allowed" })
+        );
+    }
+
+    #[tokio::test]
+    async fn large_body_is_refused() {
+        let body = vec![b' '; MAX_BODY_BYTES as usize + 1];
+        let (status, error) = send(
+            warp::test::request()
+                .method("PUT")
+                .path("/core")
+                .header("content-type", "application/json")
+                .body(body),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error["code"], "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn non_json_body_is_unsupported() {
+        let (status, error) = send(
+            warp::test::request()
+                .method("PUT")
+                .path("/core")
+                .header("content-type", "text/plain")
+                .body("bin_path=/bin/sh"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(error["code"], "unsupported_media_type");
+    }
+
+    #[tokio::test]
+    async fn invalid_start_request_lists_fields() {
+        let (status, error) = send(warp::test::request().method("PUT").path("/core").json(
+            &json!({
+                "bin_path": "mihomo",
+                "config_dir": "../config",
+                "config_file": "config.yaml",
+                "log_file": "core.log",
+            }),
+        ))
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error["code"], "validation_failed");
+        let fields: Vec<&str> = error["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| field["field"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            fields,
+            ["bin_path", "config_dir", "config_file", "log_file"]
+        );
+    }
+
+    #[tokio::test]
+    async fn other_rejections_hide_warp_details() {
+        let (status, error) = send(warp::test::request().path("/core/logs?backlog=lots")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            error,
+            json!({ "code": "bad_request", "message": "bad request" })
+        );
+    }
+
+    async fn instance_status(name: &str) -> StatusCode {
+        let path = format!("/instances/{}", name);
+        warp::test::request()
+            .path(&path)
+            .reply(&api())
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn invalid_put_creates_no_instance() {
+        let (status, _) = send(
+            warp::test::request()
+                .method("PUT")
+                .path("/instances/invalid")
+                .json(&json!({
+                    "bin_path": "mihomo",
+                    "config_dir": "config",
+                    "config_file": "config.yaml",
+                    "log_file": "core.log",
+                })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(instance_status("invalid").await, StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_put_removes_new_instance() {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let core = root.join("mihomo");
+        // 配置测试通过，正式启动时立即退出
+        fs::write(
+            &core,
+            "#!/bin/sh\ncase \"$*\" in *-t*) exit 0 ;; esac\nexit 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(&core, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(root.join("config.yaml"), "mixed-port: 7890\n").unwrap();
+        let path = |name: &str| root.join(name).to_str().unwrap().to_string();
+        instances::STATE_DIR.with(|dir| *dir.borrow_mut() = Some(root.join("run")));
+
+        let (status, error) = send(
+            warp::test::request()
+                .method("PUT")
+                .path("/instances/exits")
+                .json(&json!({
+                    "bin_path": path("mihomo"),
+                    "config_dir": path(""),
+                    "config_file": path("config.yaml"),
+                    "log_file": path("core.log"),
+                })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error["code"], "core_exited");
+        assert_eq!(instance_status("exits").await, StatusCode::NOT_FOUND);
+    }
+}
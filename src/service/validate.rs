@@ -0,0 +1,527 @@
+use super::{
+    config::ServiceConfig,
+    data::{ResourceLimits, StartBody},
+    error::{FieldError, ServiceError},
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// Size of the kernel CPU mask (`CPU_SETSIZE`)
+const MAX_CPUS: usize = 1024;
+
+/// Check a start request before anything is stored or executed, returning a
+/// copy with every path resolved so later checks and the actual spawn see
+/// the same files.
+pub fn validate_start_body(
+    body: &StartBody,
+    config: &ServiceConfig,
+) -> Result<StartBody, ServiceError> {
+    let mut errors = Vec::new();
+
+    let bin_path = check_field(&mut errors, "bin_path", &body.bin_path, check_bin_path);
+    let config_dir = check_field(&mut errors, "config_dir", &body.config_dir, check_dir);
+    let config_file = check_field(&mut errors, "config_file", &body.config_file, check_file);
+    let log_file = check_field(&mut errors, "log_file", &body.log_file, check_log_file);
+    if let Some(limits) = &body.limits {
+        errors.extend(check_limits(limits));
+    }
+    if let Some(user) = &body.user {
+        if config.core_user.as_ref() != Some(user) && !config.allowed_core_users.contains(user) {
+            errors.push(FieldError {
+                field: "user",
+                message: "is not in allowed_core_users".into(),
+            });
+        }
+    }
+    errors.extend(check_args(&body.args, &config.allowed_core_args));
+    errors.extend(check_env(&body.env, &config.allowed_core_env));
+    let working_dir = body.working_dir.as_ref().and_then(|dir| {
+        let dir = check_field(&mut errors, "working_dir", dir, check_dir)?;
+        if !config.is_allowed_work_dir(&dir) {
+            errors.push(FieldError {
+                field: "working_dir",
+                message: "is not in allowed_core_work_dirs".into(),
+            });
+        }
+        Some(dir)
+    });
+
+    if !errors.is_empty() {
+        return Err(ServiceError::Validation(errors));
+    }
+
+    let bin_path = bin_path.unwrap();
+    if !config.is_allowed_core_path(&bin_path) {
+        return Err(ServiceError::PathNotAllowed(body.bin_path.clone()));
+    }
+
+    Ok(StartBody {
+        core_type: body.core_type.clone(),
+        bin_path: path_string(bin_path),
+        config_dir: path_string(config_dir.unwrap()),
+        config_file: path_string(config_file.unwrap()),
+        log_file: path_string(log_file.unwrap()),
+        limits: body.limits.clone(),
+        user: body.user.clone(),
+        args: body.args.clone(),
+        env: body.env.clone(),
+        working_dir: working_dir.map(path_string),
+    })
+}
+
+/// `-name` of a `-name` or `-name=value` argument, `None` for anything that
+/// is not a flag
+pub fn flag_name(arg: &str) -> Option<&str> {
+    let name = arg.split_once('=').map_or(arg, |(name, _)| name);
+    let bare = name.trim_start_matches('-');
+    if bare.is_empty() || bare.len() == name.len() {
+        return None;
+    }
+    Some(name)
+}
+
+/// Portable environment variable name: letters, digits and `_`, not
+/// starting with a digit
+pub fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Every argument must be an allowed flag, with its value attached as
+/// `-flag=value`; a separate value could not be told apart from a stray
+/// argument after a flag that takes none
+fn check_args(args: &[String], allowed: &[String]) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    for arg in args {
+        let message = if arg.contains('\0') {
+            "must not contain NUL bytes".to_string()
+        } else {
+            match flag_name(arg) {
+                Some(name) if allowed.iter().any(|flag| flag == name) => continue,
+                Some(name) => format!("{} is not in allowed_core_args", name),
+                None => format!("{} is not a flag, pass values as -flag=value", arg),
+            }
+        };
+        errors.push(FieldError {
+            field: "args",
+            message,
+        });
+    }
+    errors
+}
+
+fn check_env(env: &BTreeMap<String, String>, allowed: &[String]) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    for (name, value) in env {
+        let message = if !is_env_name(name) {
+            format!("{} is not a valid variable name", name)
+        } else if !allowed.contains(name) {
+            format!("{} is not in allowed_core_env", name)
+        } else if value.contains('\0') {
+            format!("{} must not contain NUL bytes", name)
+        } else {
+            continue;
+        };
+        errors.push(FieldError {
+            field: "env",
+            message,
+        });
+    }
+    errors
+}
+
+/// Range checks for resource limits, also used for the service defaults
+pub fn check_limits(limits: &ResourceLimits) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let mut error = |field, message: &str| {
+        errors.push(FieldError {
+            field,
+            message: message.to_string(),
+        })
+    };
+
+    if cfg!(not(unix)) && !limits.is_empty() {
+        error(
+            "limits",
+            "resource limits are not supported on this platform",
+        );
+        return errors;
+    }
+    if limits.nofile == Some(0) {
+        error("limits.nofile", "must be greater than 0");
+    }
+    if limits.address_space == Some(0) {
+        error("limits.address_space", "must be greater than 0");
+    }
+    if limits.nice.is_some_and(|nice| !(-20..=19).contains(&nice)) {
+        error("limits.nice", "must be between -20 and 19");
+    }
+    if let Some(cpus) = &limits.cpu_affinity {
+        if cfg!(not(target_os = "linux")) {
+            error(
+                "limits.cpu_affinity",
+                "CPU affinity is only supported on Linux",
+            );
+        } else if cpus.is_empty() {
+            error("limits.cpu_affinity", "must list at least one CPU");
+        } else if cpus.iter().any(|&cpu| cpu >= MAX_CPUS) {
+            error("limits.cpu_affinity", "contains an invalid CPU index");
+        }
+    }
+    errors
+}
+
+fn check_field(
+    errors: &mut Vec<FieldError>,
+    field: &'static str,
+    value: &str,
+    check: fn(&Path) -> Result<PathBuf, String>,
+) -> Option<PathBuf> {
+    match check_path_syntax(value).and_then(check) {
+        Ok(path) => Some(path),
+        Err(message) => {
+            errors.push(FieldError { field, message });
+            None
+        }
+    }
+}
+
+fn path_string(path: PathBuf) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Absolute, no `..` components, nothing a shell or C string would mangle
+fn check_path_syntax(value: &str) -> Result<&Path, String> {
+    if value.is_empty() {
+        return Err("must not be empty".into());
+    }
+    if value.contains('\0') {
+        return Err("must not contain NUL bytes".into());
+    }
+    let path = Path::new(value);
+    if !path.is_absolute() {
+        return Err("must be an absolute path".into());
+    }
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err("must not contain '..'".into());
+    }
+    Ok(path)
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf, String> {
+    let canonical = fs::canonicalize(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => "does not exist".to_string(),
+        _ => format!("cannot be resolved: {}", e),
+    })?;
+    // Windows 会返回 \\?\ 前缀的路径，去掉以便与用户输入保持一致
+    #[cfg(windows)]
+    let canonical = PathBuf::from(
+        canonical
+            .to_string_lossy()
+            .trim_start_matches(r"\\?\")
+            .to_string(),
+    );
+    Ok(canonical)
+}
+
+fn check_bin_path(path: &Path) -> Result<PathBuf, String> {
+    let path = canonicalize(path)?;
+    let metadata = fs::metadata(&path).map_err(|e| e.to_string())?;
+    if !metadata.is_file() {
+        return Err("is not a regular file".into());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err("is not executable".into());
+        }
+    }
+    #[cfg(windows)]
+    if !path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("exe"))
+    {
+        return Err("is not an .exe file".into());
+    }
+    Ok(path)
+}
+
+fn check_dir(path: &Path) -> Result<PathBuf, String> {
+    let path = canonicalize(path)?;
+    if !path.is_dir() {
+        return Err("is not a directory".into());
+    }
+    Ok(path)
+}
+
+fn check_file(path: &Path) -> Result<PathBuf, String> {
+    let path = canonicalize(path)?;
+    if !path.is_file() {
+        return Err("is not a regular file".into());
+    }
+    fs::File::open(&path).map_err(|e| format!("is not readable: {}", e))?;
+    Ok(path)
+}
+
+/// The log is created by root, so it must not be a link that would let the
+/// caller truncate some other file.
+fn check_log_file(path: &Path) -> Result<PathBuf, String> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err("must name a file".into());
+    };
+    let parent = canonicalize(parent).map_err(|e| format!("parent directory {}", e))?;
+    if !parent.is_dir() {
+        return Err("parent is not a directory".into());
+    }
+    let path = parent.join(name);
+    match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => Err("must not be a symlink".into()),
+        Ok(metadata) if !metadata.is_file() => Err("is not a regular file".into()),
+        _ => Ok(path),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use tempfile::TempDir;
+
+    /// A directory with an executable core, a config and a valid request for them
+    fn setup() -> (TempDir, StartBody) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let bin = root.join("mihomo");
+        fs::write(&bin, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&bin, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(root.join("config.yaml"), "mixed-port: 7890\n").unwrap();
+        let body = StartBody {
+            bin_path: path_string(bin),
+            config_dir: path_string(root.clone()),
+            config_file: path_string(root.join("config.yaml")),
+            log_file: path_string(root.join("core.log")),
+            ..StartBody::default()
+        };
+        (dir, body)
+    }
+
+    fn field_errors(body: &StartBody) -> Vec<(&'static str, String)> {
+        match validate_start_body(body, &ServiceConfig::default()) {
+            Err(ServiceError::Validation(errors)) => {
+                errors.into_iter().map(|e| (e.field, e.message)).collect()
+            }
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_request() {
+        let (_dir, body) = setup();
+        let checked = validate_start_body(&body, &ServiceConfig::default()).unwrap();
+        assert_eq!(checked.bin_path, body.bin_path);
+        assert_eq!(checked.log_file, body.log_file);
+    }
+
+    #[test]
+    fn rejects_relative_path() {
+        assert_eq!(
+            check_path_syntax("bin/mihomo").unwrap_err(),
+            "must be an absolute path"
+        );
+        let (_dir, mut body) = setup();
+        body.config_file = "config.yaml".into();
+        assert_eq!(
+            field_errors(&body),
+            [("config_file", "must be an absolute path".to_string())]
+        );
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        let (_dir, mut body) = setup();
+        // 即使 .. 解析后仍指向同一个目录也拒绝
+        body.config_dir = format!("{}/sub/..", body.config_dir);
+        assert_eq!(
+            field_errors(&body),
+            [("config_dir", "must not contain '..'".to_string())]
+        );
+    }
+
+    #[test]
+    fn rejects_empty_and_nul_paths() {
+        assert_eq!(check_path_syntax("").unwrap_err(), "must not be empty");
+        assert_eq!(
+            check_path_syntax("/usr/bin/mihomo\0").unwrap_err(),
+            "must not contain NUL bytes"
+        );
+    }
+
+    #[test]
+    fn rejects_missing_file() {
+        let (dir, mut body) = setup();
+        body.config_file = path_string(dir.path().join("missing.yaml"));
+        assert_eq!(
+            field_errors(&body),
+            [("config_file", "does not exist".to_string())]
+        );
+    }
+
+    #[test]
+    fn rejects_non_executable_bin_path() {
+        let (_dir, body) = setup();
+        fs::set_permissions(&body.bin_path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            check_bin_path(Path::new(&body.bin_path)).unwrap_err(),
+            "is not executable"
+        );
+        assert_eq!(
+            field_errors(&body),
+            [("bin_path", "is not executable".to_string())]
+        );
+    }
+
+    #[test]
+    fn rejects_directory_as_bin_path() {
+        let (_dir, body) = setup();
+        assert_eq!(
+            check_bin_path(Path::new(&body.config_dir)).unwrap_err(),
+            "is not a regular file"
+        );
+    }
+
+    #[test]
+    fn rejects_symlinked_log_file() {
+        let (dir, mut body) = setup();
+        let link = dir.path().join("core.log");
+        symlink("/etc/passwd", &link).unwrap();
+        body.log_file = path_string(link);
+        assert_eq!(
+            field_errors(&body),
+            [("log_file", "must not be a symlink".to_string())]
+        );
+    }
+
+    #[test]
+    fn rejects_log_file_without_parent() {
+        let (dir, mut body) = setup();
+        body.log_file = path_string(dir.path().join("logs").join("core.log"));
+        assert_eq!(
+            field_errors(&body),
+            [("log_file", "parent directory does not exist".to_string())]
+        );
+    }
+
+    #[test]
+    fn reports_every_invalid_field() {
+        let (dir, mut body) = setup();
+        fs::set_permissions(&body.bin_path, fs::Permissions::from_mode(0o600)).unwrap();
+        body.config_dir = "relative".into();
+        body.config_file = path_string(dir.path().join("missing.yaml"));
+        body.log_file = format!("{}/../core.log", body.log_file);
+        body.limits = Some(ResourceLimits {
+            nofile: Some(0),
+            ..ResourceLimits::default()
+        });
+        let fields: Vec<&str> = field_errors(&body)
+            .into_iter()
+            .map(|(field, _)| field)
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "bin_path",
+                "config_dir",
+                "config_file",
+                "log_file",
+                "limits.nofile"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bin_path_outside_allowed_dirs() {
+        let (_dir, body) = setup();
+        let config = ServiceConfig {
+            allowed_core_dirs: vec![PathBuf::from("/opt/ssrapid/cores")],
+            ..ServiceConfig::default()
+        };
+        assert!(matches!(
+            validate_start_body(&body, &config),
+            Err(ServiceError::PathNotAllowed(_))
+        ));
+    }
+
+    fn args_errors(args: &[&str]) -> Vec<String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let allowed = ["-ext-ctl".to_string(), "-allowed-bool".to_string()];
+        check_args(&args, &allowed)
+            .into_iter()
+            .map(|e| e.message)
+            .collect()
+    }
+
+    #[test]
+    fn accepts_allowed_args() {
+        assert!(args_errors(&["-ext-ctl=127.0.0.1:9090", "-allowed-bool"]).is_empty());
+    }
+
+    #[test]
+    fn rejects_disallowed_flag() {
+        assert_eq!(
+            args_errors(&["-secret=x"]),
+            ["-secret is not in allowed_core_args"]
+        );
+    }
+
+    #[test]
+    fn rejects_stray_value() {
+        assert_eq!(
+            args_errors(&["-allowed-bool", "/etc/shadow"]),
+            ["/etc/shadow is not a flag, pass values as -flag=value"]
+        );
+        assert_eq!(
+            args_errors(&["-ext-ctl", ":9090"]),
+            [":9090 is not a flag, pass values as -flag=value"]
+        );
+    }
+
+    #[test]
+    fn rejects_nul_in_args() {
+        assert_eq!(
+            args_errors(&["-ext-ctl=:90\090"]),
+            ["must not contain NUL bytes"]
+        );
+    }
+
+    fn env_errors(name: &str, value: &str) -> Vec<String> {
+        let env = BTreeMap::from([(name.to_string(), value.to_string())]);
+        check_env(&env, &["SAFE_PATHS".to_string()])
+            .into_iter()
+            .map(|e| e.message)
+            .collect()
+    }
+
+    #[test]
+    fn checks_env() {
+        assert!(env_errors("SAFE_PATHS", "/etc/mihomo").is_empty());
+        assert_eq!(
+            env_errors("BAD-NAME", "x"),
+            ["BAD-NAME is not a valid variable name"]
+        );
+        assert_eq!(
+            env_errors("LD_PRELOAD", "/tmp/evil.so"),
+            ["LD_PRELOAD is not in allowed_core_env"]
+        );
+        assert_eq!(
+            env_errors("SAFE_PATHS", "/etc\0"),
+            ["SAFE_PATHS must not contain NUL bytes"]
+        );
+    }
+}
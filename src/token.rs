@@ -0,0 +1,95 @@
+use anyhow::{anyhow, Error};
+use rand::RngCore;
+use std::path::{Path, PathBuf};
+
+const TOKEN_BYTES: usize = 32;
+
+/// Location of the shared secret the desktop app presents to the service
+pub fn token_file_path() -> PathBuf {
+    #[cfg(windows)]
+    {
+        let program_data =
+            std::env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".into());
+        PathBuf::from(program_data)
+            .join("ssrapid")
+            .join("service.token")
+    }
+
+    #[cfg(not(windows))]
+    {
+        PathBuf::from("/etc/ssrapid/service.token")
+    }
+}
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn read_token_file(path: &Path) -> Result<String, Error> {
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read token file {}: {}", path.display(), e))?;
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        return Err(anyhow!("Token file {} is empty", path.display()));
+    }
+    Ok(token)
+}
+
+/// Write the token so that only root (or SYSTEM/Administrators) can read it
+pub fn write_token_file(path: &Path, token: &str) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| anyhow!("Failed to create token directory: {}", e))?;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| anyhow!("Failed to open token file: {}", e))?;
+        // 文件可能已存在且权限过宽
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| anyhow!("Failed to set token file permissions: {}", e))?;
+        file.write_all(token.as_bytes())
+            .map_err(|e| anyhow!("Failed to write token file: {}", e))?;
+    }
+
+    #[cfg(windows)]
+    {
+        std::fs::write(path, token).map_err(|e| anyhow!("Failed to write token file: {}", e))?;
+        // 去掉继承自 ProgramData 的 Users 读权限
+        let path = path.to_string_lossy();
+        crate::utils::run_command(
+            "icacls",
+            &[
+                &path,
+                "/inheritance:r",
+                "/grant:r",
+                "*S-1-5-18:F",
+                "*S-1-5-32-544:F",
+            ],
+            false,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Read the existing token, generating and persisting a new one if missing
+pub fn load_or_create_token(path: &Path) -> Result<String, Error> {
+    if path.exists() {
+        return read_token_file(path);
+    }
+    let token = generate_token();
+    write_token_file(path, &token)?;
+    Ok(token)
+}